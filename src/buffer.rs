@@ -0,0 +1,175 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Returns the part of `data` covered by a read of `size` bytes at `offset`.
+pub fn slice(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let from = (offset.max(0) as usize).min(data.len());
+    let to = from.saturating_add(size as usize).min(data.len());
+    &data[from..to]
+}
+
+/// Decides which transform outputs are kept in memory.
+///
+/// An output moves to a temporary file once it grows beyond `threshold`, or
+/// once the outputs kept in memory would together exceed `limit`.
+pub struct Memory {
+    threshold: u64,
+    limit: u64,
+    used: AtomicU64,
+    spill_dir: PathBuf,
+}
+
+impl Memory {
+    pub fn new(threshold: u64, limit: u64, spill_dir: PathBuf) -> Self {
+        Memory {
+            threshold,
+            limit,
+            used: AtomicU64::new(0),
+            spill_dir,
+        }
+    }
+
+    fn reserve(&self, len: u64) -> bool {
+        self.used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                Some(used + len).filter(|used| *used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, len: u64) {
+        self.used.fetch_sub(len, Ordering::SeqCst);
+    }
+
+    /// Creates an anonymous file in the spill directory, which disappears
+    /// once it is closed.
+    fn spill_file(&self) -> io::Result<File> {
+        let tmpfile = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .mode(0o600)
+            .custom_flags(libc::O_TMPFILE)
+            .open(&self.spill_dir);
+        match tmpfile {
+            Ok(file) => Ok(file),
+            // not every file system supports O_TMPFILE
+            Err(_) => {
+                static NEXT: AtomicU64 = AtomicU64::new(0);
+                let path = self.spill_dir.join(format!(
+                    ".shellfs-{}-{}",
+                    process::id(),
+                    NEXT.fetch_add(1, Ordering::SeqCst)
+                ));
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&path)?;
+                fs::remove_file(&path)?;
+                Ok(file)
+            }
+        }
+    }
+}
+
+enum Storage {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+/// Transform output, held in memory or in a temporary file as `Memory`
+/// decides.
+pub struct Buffer {
+    memory: Arc<Memory>,
+    storage: Storage,
+    len: u64,
+}
+
+impl Buffer {
+    pub fn new(memory: &Arc<Memory>) -> Self {
+        Buffer {
+            memory: memory.clone(),
+            storage: Storage::Memory(Vec::new()),
+            len: 0,
+        }
+    }
+
+    /// Wraps a file which already holds the output, such as a disk cache
+    /// entry.
+    pub fn from_file(memory: &Arc<Memory>, file: File, len: u64) -> Self {
+        Buffer {
+            memory: memory.clone(),
+            storage: Storage::File(file),
+            len,
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let chunk_len = chunk.len() as u64;
+        if let Storage::Memory(data) = &mut self.storage {
+            if self.len + chunk_len <= self.memory.threshold && self.memory.reserve(chunk_len) {
+                data.extend_from_slice(chunk);
+                self.len += chunk_len;
+                return Ok(());
+            }
+            let file = self.memory.spill_file()?;
+            file.write_all_at(data, 0)?;
+            self.memory.release(data.len() as u64);
+            self.storage = Storage::File(file);
+        }
+        if let Storage::File(file) = &self.storage {
+            file.write_all_at(chunk, self.len)?;
+        }
+        self.len += chunk_len;
+        Ok(())
+    }
+
+    /// Returns the bytes covered by a read of `size` bytes at `offset`.
+    pub fn read(&self, offset: i64, size: u32) -> io::Result<Vec<u8>> {
+        match &self.storage {
+            Storage::Memory(data) => Ok(slice(data, offset, size).to_vec()),
+            Storage::File(file) => {
+                let offset = offset.max(0) as u64;
+                let len = self.len.saturating_sub(offset).min(u64::from(size));
+                let mut data = vec![0; len as usize];
+                file.read_exact_at(&mut data, offset)?;
+                Ok(data)
+            }
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match &self.storage {
+            Storage::Memory(data) => out.write_all(data),
+            Storage::File(file) => {
+                let mut buf = vec![0; 64 * 1024];
+                let mut offset = 0;
+                while offset < self.len {
+                    let len = (self.len - offset).min(buf.len() as u64) as usize;
+                    file.read_exact_at(&mut buf[..len], offset)?;
+                    out.write_all(&buf[..len])?;
+                    offset += len as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Storage::Memory(data) = &self.storage {
+            self.memory.release(data.len() as u64);
+        }
+    }
+}
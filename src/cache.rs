@@ -0,0 +1,180 @@
+use crate::buffer::Buffer;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Parses a byte count with an optional binary `K`, `M`, `G` or `T` suffix.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (digits, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 30),
+        Some('T') | Some('t') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("expected a size such as `512M` or `2G`, got `{}`", s))
+}
+
+/// 64-bit FNV-1a, used because it is stable across builds and platforms.
+pub fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        // separate the parts so that ("ab", "c") and ("a", "bc") differ
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+struct Entry {
+    size: u64,
+    used: SystemTime,
+}
+
+/// Transform outputs stored on disk, evicted least recently used first once
+/// their total size exceeds `max_size`.
+///
+/// The last use of an entry is recorded as its file's mtime, so the eviction
+/// order survives remounts.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    entries: HashMap<String, Entry>,
+    total: u64,
+}
+
+impl DiskCache {
+    pub fn open(dir: PathBuf, max_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut entries = HashMap::new();
+        let mut total = 0;
+        for dir_entry in fs::read_dir(&dir)? {
+            let dir_entry = dir_entry?;
+            let name = match dir_entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if name.ends_with(".tmp") {
+                // left behind by an interrupted `put`
+                let _ = fs::remove_file(dir_entry.path());
+                continue;
+            }
+            let metadata = dir_entry.metadata()?;
+            total += metadata.len();
+            entries.insert(
+                name,
+                Entry {
+                    size: metadata.len(),
+                    used: metadata.modified()?,
+                },
+            );
+        }
+        info!(
+            "Opened transform cache {:?}: {} entries, {} bytes",
+            dir,
+            entries.len(),
+            total
+        );
+        let mut cache = DiskCache {
+            dir,
+            max_size,
+            entries,
+            total,
+        };
+        cache.evict();
+        Ok(cache)
+    }
+
+    /// Builds the cache key for the output of `command` on `path`, given a
+    /// fingerprint of the source that changes whenever the output would.
+    pub fn key(path: &Path, command: &str, fingerprint: &[u8]) -> String {
+        format!(
+            "{:016x}",
+            fnv1a(&[path.as_os_str().as_bytes(), command.as_bytes(), fingerprint])
+        )
+    }
+
+    /// Opens the entry for `key`, returning it along with its length.
+    pub fn get(&mut self, key: &str) -> Option<(File, u64)> {
+        let entry = self.entries.get_mut(key)?;
+        let path = self.dir.join(key);
+        match File::open(&path) {
+            Ok(file) => {
+                entry.used = SystemTime::now();
+                if let Err(e) = file.set_modified(entry.used) {
+                    warn!("Failed to mark cache entry {:?} as used: {}", path, e);
+                }
+                Some((file, entry.size))
+            }
+            Err(e) => {
+                warn!("Failed to read cache entry {:?}: {}", path, e);
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    pub fn put(&mut self, key: &str, data: &Buffer) {
+        if data.len() > self.max_size {
+            return;
+        }
+        let path = self.dir.join(key);
+        let tmp = self.dir.join(format!("{}.tmp", key));
+        let written = File::create(&tmp)
+            .and_then(|mut file| data.write_to(&mut file))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = written {
+            warn!("Failed to write cache entry {:?}: {}", path, e);
+            let _ = fs::remove_file(&tmp);
+            return;
+        }
+        if let Some(old) = self.entries.insert(
+            key.to_owned(),
+            Entry {
+                size: data.len(),
+                used: SystemTime::now(),
+            },
+        ) {
+            self.total -= old.size;
+        }
+        self.total += data.len();
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.total -= entry.size;
+            let _ = fs::remove_file(self.dir.join(key));
+        }
+    }
+
+    fn evict(&mut self) {
+        if self.total <= self.max_size {
+            return;
+        }
+        let mut by_use: Vec<(SystemTime, String)> = self
+            .entries
+            .iter()
+            .map(|(key, entry)| (entry.used, key.clone()))
+            .collect();
+        by_use.sort();
+        for (_, key) in by_use {
+            if self.total <= self.max_size {
+                break;
+            }
+            self.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use log::warn;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// An error number reported back to the kernel.
+pub type Errno = libc::c_int;
+
+const ERRNO_NAMES: &[(&str, Errno)] = &[
+    ("EPERM", libc::EPERM),
+    ("ENOENT", libc::ENOENT),
+    ("EIO", libc::EIO),
+    ("EAGAIN", libc::EAGAIN),
+    ("EACCES", libc::EACCES),
+    ("EBUSY", libc::EBUSY),
+    ("EEXIST", libc::EEXIST),
+    ("EISDIR", libc::EISDIR),
+    ("EINVAL", libc::EINVAL),
+    ("EFBIG", libc::EFBIG),
+    ("ENOSPC", libc::ENOSPC),
+    ("EROFS", libc::EROFS),
+    ("ENODATA", libc::ENODATA),
+    ("ENOTSUP", libc::ENOTSUP),
+    ("ETIMEDOUT", libc::ETIMEDOUT),
+];
+
+/// Maps an exit status of a command to an errno, written `CODE=ERRNO`, e.g.
+/// `2=ENOENT`.
+#[derive(Debug, Clone, Copy)]
+pub struct ExitMapping {
+    pub code: i32,
+    pub errno: Errno,
+}
+
+impl FromStr for ExitMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let code = parts.next().and_then(|code| code.parse().ok());
+        let errno = parts.next().and_then(|errno| {
+            ERRNO_NAMES
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(errno))
+                .map(|(_, errno)| *errno)
+                .or_else(|| errno.parse().ok())
+        });
+        match (code, errno) {
+            (Some(code), Some(errno)) => Ok(ExitMapping { code, errno }),
+            _ => Err(format!(
+                "expected `EXIT_CODE=ERRNO` such as `2=ENOENT`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// How failing commands are reported.
+#[derive(Debug, Default)]
+pub struct ErrorPolicy {
+    /// Serve the output of commands even when they exit with a non-zero
+    /// status.
+    pub ignore_status: bool,
+    /// Errnos for specific exit codes; any other failure is `EIO`.
+    pub errnos: HashMap<i32, Errno>,
+}
+
+impl ErrorPolicy {
+    pub fn new(ignore_status: bool, mappings: &[ExitMapping]) -> Self {
+        ErrorPolicy {
+            ignore_status,
+            errnos: mappings.iter().map(|m| (m.code, m.errno)).collect(),
+        }
+    }
+
+    /// Runs a command which gets `input` as `$INPUT` and returns its stdout,
+    /// or the errno the failure maps to. Failures are logged together with
+    /// the command's stderr.
+    pub fn run(
+        &self,
+        what: &str,
+        command: &str,
+        input: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, Errno> {
+        self.run_with_env(what, command, input, &[], timeout)
+    }
+
+    /// Like `run`, with further environment variables for the command.
+    pub fn run_with_env(
+        &self,
+        what: &str,
+        command: &str,
+        input: &Path,
+        env: &[(&str, &OsStr)],
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, Errno> {
+        let mut vars = vec![("INPUT", input.as_os_str())];
+        vars.extend_from_slice(env);
+        self.check(what, input, run(command, &vars, timeout))
+    }
+
+    /// Like `run`, with `stdin` piped to the command.
+    pub fn run_with_stdin(
+        &self,
+        what: &str,
+        command: &str,
+        input: &Path,
+        stdin: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, Errno> {
+        let vars = [("INPUT", input.as_os_str())];
+        self.check(what, input, run_with_stdin(command, &vars, stdin, timeout))
+    }
+
+    /// Like `run`, but hands the command's stdout to `sink` as it is
+    /// produced.
+    pub fn stream<F: FnMut(&[u8])>(
+        &self,
+        what: &str,
+        command: &str,
+        input: &Path,
+        timeout: Option<Duration>,
+        sink: F,
+    ) -> Result<(), Errno> {
+        let vars = [("INPUT", input.as_os_str())];
+        let result = stream(command, &vars, timeout, sink).map(|()| Vec::new());
+        self.check(what, input, result).map(|_| ())
+    }
+
+    fn check(
+        &self,
+        what: &str,
+        input: &Path,
+        result: Result<Vec<u8>, Error>,
+    ) -> Result<Vec<u8>, Errno> {
+        match result {
+            Ok(stdout) => Ok(stdout),
+            Err(Error::Status { code, stdout, .. }) if self.ignore_status => {
+                warn!(
+                    "{} command exited with {:?} for {:?}, ignoring",
+                    what, code, input
+                );
+                Ok(stdout)
+            }
+            Err(e) => {
+                warn!("{} command failed for {:?}: {}", what, input, e);
+                Err(match e {
+                    Error::Status {
+                        code: Some(code), ..
+                    } => self.errnos.get(&code).copied().unwrap_or(libc::EIO),
+                    Error::Timeout(_) => libc::ETIMEDOUT,
+                    _ => libc::EIO,
+                })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// `sh` could not be started.
+    Spawn(io::Error),
+    /// The command exited with a non-zero status, or was killed by a signal
+    /// if there is no exit code.
+    Status {
+        code: Option<i32>,
+        stdout: Vec<u8>,
+        stderr: Vec<u8>,
+    },
+    /// The command was killed for running longer than this.
+    Timeout(Duration),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Spawn(e) => write!(f, "couldn't run sh: {}", e),
+            Error::Status {
+                code: Some(code),
+                stderr,
+                ..
+            } => write!(
+                f,
+                "exited with status {}: {}",
+                code,
+                String::from_utf8_lossy(stderr).trim_end()
+            ),
+            Error::Status {
+                code: None, stderr, ..
+            } => write!(
+                f,
+                "killed by a signal: {}",
+                String::from_utf8_lossy(stderr).trim_end()
+            ),
+            Error::Timeout(timeout) => write!(f, "timed out after {:?}", timeout),
+        }
+    }
+}
+
+/// Runs `command` with `sh -c`, with `env` added to its environment.
+pub fn run(
+    command: &str,
+    env: &[(&str, &OsStr)],
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, Error> {
+    collect(command, env, None, timeout)
+}
+
+/// Like `run`, with `stdin` piped to the command.
+pub fn run_with_stdin(
+    command: &str,
+    env: &[(&str, &OsStr)],
+    stdin: Vec<u8>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, Error> {
+    collect(command, env, Some(stdin), timeout)
+}
+
+fn collect(
+    command: &str,
+    env: &[(&str, &OsStr)],
+    stdin: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+) -> Result<Vec<u8>, Error> {
+    let mut stdout = Vec::new();
+    match spawn(command, env, stdin, timeout, |chunk| {
+        stdout.extend_from_slice(chunk)
+    }) {
+        Ok(()) => Ok(stdout),
+        Err(Error::Status { code, stderr, .. }) => Err(Error::Status {
+            code,
+            stdout,
+            stderr,
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs `command` like `run`, handing its stdout to `sink` as it is
+/// produced.
+///
+/// The command runs in a process group of its own, so that if it takes
+/// longer than `timeout` everything it started can be killed along with it.
+pub fn stream<F: FnMut(&[u8])>(
+    command: &str,
+    env: &[(&str, &OsStr)],
+    timeout: Option<Duration>,
+    sink: F,
+) -> Result<(), Error> {
+    spawn(command, env, None, timeout, sink)
+}
+
+fn spawn<F: FnMut(&[u8])>(
+    command: &str,
+    env: &[(&str, &OsStr)],
+    stdin: Option<Vec<u8>>,
+    timeout: Option<Duration>,
+    mut sink: F,
+) -> Result<(), Error> {
+    let mut sh = Command::new("sh");
+    sh.arg("-c")
+        .arg(command)
+        .stdin(if stdin.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    for (name, value) in env {
+        sh.env(name, value);
+    }
+    let mut child = sh.spawn().map_err(Error::Spawn)?;
+    // read stderr in the background, so that a command filling it doesn't
+    // block forever
+    let stderr = read_to_end(child.stderr.take());
+    // likewise write stdin in the background, so that a command which
+    // writes output before reading all of its input doesn't block forever
+    let writer = match (stdin, child.stdin.take()) {
+        (Some(data), Some(mut pipe)) => Some(thread::spawn(move || {
+            // the command may exit without reading everything
+            let _ = pipe.write_all(&data);
+        })),
+        _ => None,
+    };
+
+    let pgid = child.id() as libc::pid_t;
+    let (exited, exit) = mpsc::channel::<()>();
+    let watchdog = timeout.map(|timeout| {
+        thread::spawn(move || match exit.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                unsafe {
+                    libc::kill(-pgid, libc::SIGKILL);
+                }
+                true
+            }
+            _ => false,
+        })
+    });
+
+    if let Some(mut stdout) = child.stdout.take() {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match stdout.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => sink(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    }
+    let status = child.wait();
+    let _ = exited.send(());
+    if let Some(writer) = writer {
+        let _ = writer.join();
+    }
+    let killed = watchdog.is_some_and(|watchdog| watchdog.join().unwrap_or(false));
+    let status = status.map_err(Error::Spawn)?;
+    let stderr = stderr.join().unwrap_or_default();
+
+    match timeout {
+        Some(timeout) if killed => Err(Error::Timeout(timeout)),
+        _ if status.success() => Ok(()),
+        _ => Err(Error::Status {
+            code: status.code(),
+            stdout: Vec::new(),
+            stderr,
+        }),
+    }
+}
+
+fn read_to_end<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
@@ -0,0 +1,149 @@
+use crate::rule::RuleSpec;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use toml::Value;
+
+/// How a config key turns into flags.
+enum Kind {
+    /// `--flag=value`
+    Value,
+    /// `--flag` if the value is `true`
+    Switch,
+    /// `--flag=value` for each value in an array
+    List,
+    /// `--rule=json` for each table in an array
+    Rules,
+}
+
+/// The keys a config file may set, with the flag each one stands for.
+const KEYS: &[(&str, &str, Kind)] = &[
+    ("mountpoint", "--mountpoint", Kind::Value),
+    ("mount_options", "--option", Kind::List),
+    ("list", "--list", Kind::Value),
+    ("list_format", "--list-format", Kind::Value),
+    ("strip_prefix", "--strip-prefix", Kind::Value),
+    ("relative_to", "--relative-to", Kind::Value),
+    ("flatten", "--flatten", Kind::Switch),
+    ("name", "--name", Kind::Value),
+    ("name_command", "--name-command", Kind::Value),
+    ("transform", "--transform", Kind::Value),
+    ("rules", "--rule", Kind::Rules),
+    ("refresh", "--refresh", Kind::Value),
+    ("size_strategy", "--size-strategy", Kind::Value),
+    ("size", "--size", Kind::Value),
+    ("stat", "--stat", Kind::Value),
+    ("write", "--write", Kind::Value),
+    ("create", "--create", Kind::Value),
+    ("unlink", "--unlink", Kind::Value),
+    ("rename", "--rename", Kind::Value),
+    ("mkdir", "--mkdir", Kind::Value),
+    ("rmdir", "--rmdir", Kind::Value),
+    ("cache_dir", "--cache-dir", Kind::Value),
+    ("cache_size", "--cache-size", Kind::Value),
+    ("fingerprint", "--fingerprint", Kind::Value),
+    ("source_files", "--source-files", Kind::Switch),
+    ("watch", "--watch", Kind::Switch),
+    ("ignore_exit_status", "--ignore-exit-status", Kind::Switch),
+    ("errnos", "--errno", Kind::List),
+    ("transform_timeout", "--transform-timeout", Kind::Value),
+    ("list_timeout", "--list-timeout", Kind::Value),
+    ("jobs", "--jobs", Kind::Value),
+    ("spill_threshold", "--spill-threshold", Kind::Value),
+    ("memory_limit", "--memory-limit", Kind::Value),
+    ("spill_dir", "--spill-dir", Kind::Value),
+    ("range_transform", "--range-transform", Kind::Switch),
+];
+
+/// Reads a TOML config file, returning the command line flags it stands
+/// for, so that flags given on the command line can override them.
+///
+/// Keys are named like the flags, with `_` in place of `-`, except for
+/// `mount_options`, `errnos` and `rules`, which are arrays; each `[[rules]]`
+/// table holds the fields of a `--rule`. Every problem with the file is
+/// returned, not just the first.
+pub fn load(path: &Path) -> Result<Vec<OsString>, Vec<String>> {
+    let text =
+        fs::read_to_string(path).map_err(|e| vec![format!("can't read {:?}: {}", path, e)])?;
+    let config: Value = text
+        .parse()
+        .map_err(|e| vec![format!("can't parse {:?}: {}", path, e)])?;
+    let table = match &config {
+        Value::Table(table) => table,
+        _ => return Err(vec![format!("expected {:?} to hold a table", path)]),
+    };
+
+    let mut args = Vec::new();
+    let mut errors = Vec::new();
+    for (key, value) in table {
+        let (flag, kind) = match KEYS.iter().find(|(name, _, _)| name == key) {
+            Some((_, flag, kind)) => (*flag, kind),
+            None => {
+                errors.push(format!("unknown key `{}`", key));
+                continue;
+            }
+        };
+        let added = match (kind, value) {
+            (Kind::Switch, Value::Boolean(true)) => Ok(vec![OsString::from(flag)]),
+            (Kind::Switch, Value::Boolean(false)) => Ok(vec![]),
+            (Kind::Switch, _) => Err("expected `true` or `false`".to_owned()),
+            (Kind::Value, value) => scalar(value).map(|value| vec![flag_value(flag, &value)]),
+            (Kind::List, Value::Array(values)) => values
+                .iter()
+                .map(|value| scalar(value).map(|value| flag_value(flag, &value)))
+                .collect(),
+            (Kind::Rules, Value::Array(rules)) => rules
+                .iter()
+                .enumerate()
+                .map(|(i, rule)| {
+                    let rule = to_json(rule);
+                    RuleSpec::from_json(&rule)
+                        .map(|_| flag_value(flag, &rule.to_string()))
+                        .map_err(|e| format!("rule {}: {}", i + 1, e))
+                })
+                .collect(),
+            (_, _) => Err("expected an array".to_owned()),
+        };
+        match added {
+            Ok(added) => args.extend(added),
+            Err(e) => errors.push(format!("`{}`: {}", key, e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(args)
+    } else {
+        Err(errors)
+    }
+}
+
+fn flag_value(flag: &str, value: &str) -> OsString {
+    OsString::from(format!("{}={}", flag, value))
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(n) => Ok(n.to_string()),
+        _ => Err(format!(
+            "expected a string or an integer, got {}",
+            value.type_str()
+        )),
+    }
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::String(s) => serde_json::Value::from(s.clone()),
+        Value::Integer(n) => serde_json::Value::from(*n),
+        Value::Float(f) => serde_json::Value::from(*f),
+        Value::Boolean(b) => serde_json::Value::from(*b),
+        Value::Datetime(datetime) => serde_json::Value::from(datetime.to_string()),
+        Value::Array(values) => serde_json::Value::Array(values.iter().map(to_json).collect()),
+        Value::Table(table) => serde_json::Value::Object(
+            table
+                .iter()
+                .map(|(key, value)| (key.clone(), to_json(value)))
+                .collect(),
+        ),
+    }
+}
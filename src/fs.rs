@@ -0,0 +1,1289 @@
+use crate::buffer::{self, Buffer, Memory};
+use crate::cache::DiskCache;
+use crate::command::{Errno, ErrorPolicy};
+use crate::handles::{Contents, Draft, Handles, OpenFile};
+use crate::jobs::{InFlight, Jobs, Workers};
+use crate::list::PathMapping;
+use crate::rule::Rules;
+use crate::size::{self, SizeStrategy};
+use crate::snapshot::Snapshot;
+use crate::source::{self, Watcher};
+use crate::spool::Spool;
+use crate::stat::{self, Stat};
+use crate::Options;
+use fuse::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request,
+};
+use libc::{
+    EBADF, EEXIST, EINVAL, EIO, EISDIR, ENODATA, ENOENT, ENOTDIR, ENOTEMPTY, ERANGE, EROFS, EXDEV,
+};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TTL: Duration = Duration::from_secs(0);
+
+/// Worker threads per `--jobs`.
+const WORKERS_PER_JOB: usize = 4;
+
+/// Requests which may wait for a free worker before the kernel's requests
+/// are no longer read.
+const QUEUED_REQUESTS: usize = 1024;
+
+fn attr(ino: u64, kind: FileType, size: u64, stat: &Stat) -> FileAttr {
+    let mtime = stat.mtime.unwrap_or(UNIX_EPOCH);
+    FileAttr {
+        ino,
+        size,
+        blocks: size.div_ceil(512),
+        atime: mtime,
+        mtime,
+        ctime: mtime,
+        crtime: mtime,
+        kind,
+        perm: stat.mode.unwrap_or(0o644),
+        nlink: 1,
+        uid: stat.uid.unwrap_or(0),
+        gid: stat.gid.unwrap_or(0),
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+/// Replies with an xattr value or name list, or just its length if the
+/// caller asked for that by passing a size of 0.
+fn reply_xattr(reply: ReplyXattr, size: u32, data: &[u8]) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if (size as usize) < data.len() {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// The file system.
+///
+/// Requests which may run commands are handed to a pool of worker threads,
+/// so that a slow transform doesn't hold up the rest of the mount. The
+/// number of commands running at once is bounded by `--jobs`.
+pub struct ShellFS {
+    inner: Arc<Inner>,
+    workers: Workers,
+}
+
+struct Inner {
+    rules: Arc<Rules>,
+    size: Option<String>,
+    stat: Option<String>,
+    write: Option<String>,
+    create: Option<String>,
+    unlink: Option<String>,
+    rename: Option<String>,
+    mkdir: Option<String>,
+    rmdir: Option<String>,
+    fingerprint: Option<String>,
+    errors: ErrorPolicy,
+    timeout: Option<Duration>,
+    source_files: bool,
+    range_transform: bool,
+    jobs: Jobs,
+    transforms: InFlight<PathBuf, Result<Arc<Buffer>, Errno>>,
+    memory: Arc<Memory>,
+    /// The largest file which can be written, since drafts are kept in
+    /// memory.
+    max_draft: u64,
+    /// Locked on its own, so that copying outputs to disk doesn't block
+    /// other requests.
+    disk_cache: Option<Mutex<DiskCache>>,
+    state: Mutex<State>,
+    /// Signalled when the list command finishes.
+    refreshed: Condvar,
+}
+
+/// Everything that changes while the file system is mounted. The lock is
+/// never held while a command runs.
+struct State {
+    snapshot: Snapshot,
+    handles: Handles,
+    watcher: Option<Watcher>,
+    /// The snapshot generation `sizes` and `stats` were computed for.
+    generation: u64,
+    sizes: HashMap<PathBuf, u64>,
+    stats: HashMap<PathBuf, Stat>,
+    /// Transforms currently being streamed.
+    spools: HashMap<PathBuf, Arc<Spool>>,
+    /// Source file fingerprints the cached values for each path belong to.
+    fingerprints: HashMap<PathBuf, Vec<u8>>,
+    /// The disk cache key last used for each path.
+    disk_keys: HashMap<PathBuf, String>,
+}
+
+impl ShellFS {
+    pub fn new(options: Options) -> Self {
+        let cache_size = options.cache_size;
+        let rules = Arc::new(options.rules());
+        let disk_cache = options
+            .cache_dir
+            .map(|dir| DiskCache::open(dir, cache_size).expect("Couldn't open cache directory."));
+        let watcher = if options.watch {
+            Some(Watcher::new().expect("Couldn't initialize inotify."))
+        } else {
+            None
+        };
+        let jobs = options.jobs.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(4)
+        });
+        let state = State {
+            snapshot: Snapshot::new(
+                options.list.expect("--list is required."),
+                options.list_format,
+                PathMapping {
+                    strip_prefix: options.strip_prefix,
+                    relative_to: options.relative_to,
+                    flatten: options.flatten,
+                    rules: rules.clone(),
+                },
+                options.refresh,
+                options.list_timeout.map(Duration::from_secs),
+            ),
+            handles: Handles::default(),
+            watcher,
+            generation: 0,
+            sizes: HashMap::new(),
+            stats: HashMap::new(),
+            spools: HashMap::new(),
+            fingerprints: HashMap::new(),
+            disk_keys: HashMap::new(),
+        };
+        ShellFS {
+            inner: Arc::new(Inner {
+                rules,
+                size: options.size,
+                stat: options.stat,
+                write: options.write,
+                create: options.create,
+                unlink: options.unlink,
+                rename: options.rename,
+                mkdir: options.mkdir,
+                rmdir: options.rmdir,
+                fingerprint: options.fingerprint,
+                errors: ErrorPolicy::new(options.ignore_exit_status, &options.errnos),
+                timeout: options.transform_timeout.map(Duration::from_secs),
+                source_files: options.source_files,
+                range_transform: options.range_transform,
+                jobs: Jobs::new(jobs),
+                transforms: InFlight::new(),
+                max_draft: options.memory_limit,
+                memory: Arc::new(Memory::new(
+                    options.spill_threshold,
+                    options.memory_limit,
+                    options.spill_dir.unwrap_or_else(std::env::temp_dir),
+                )),
+                disk_cache: disk_cache.map(Mutex::new),
+                state: Mutex::new(state),
+                refreshed: Condvar::new(),
+            }),
+            // most workers spend their time waiting for a job slot or for
+            // output, so have a few per job
+            workers: Workers::new(jobs * WORKERS_PER_JOB, QUEUED_REQUESTS),
+        }
+    }
+
+    /// Handles a request on a worker thread, once the list command has run.
+    fn spawn<F: FnOnce(&Arc<Inner>) + Send + 'static>(&self, f: F) {
+        let inner = self.inner.clone();
+        self.workers.run(move || {
+            inner.refresh();
+            f(&inner)
+        });
+    }
+}
+
+impl State {
+    /// Returns the source path and kind of `ino`, dropping cached sizes and
+    /// metadata if the list has been refreshed since they were computed.
+    fn inode(&mut self, ino: u64) -> Option<(PathBuf, FileType)> {
+        let inodes = self.snapshot.inodes();
+        let inode = inodes
+            .get(ino)
+            .map(|inode| (inodes.source(inode), inode.kind));
+        if self.snapshot.generation() != self.generation {
+            self.generation = self.snapshot.generation();
+            self.sizes.clear();
+            self.stats.clear();
+            self.fingerprints.clear();
+        }
+        inode
+    }
+
+    /// Drops everything cached for `path` if its source file changed since
+    /// the cached values were computed, returning the disk cache keys which
+    /// went stale.
+    fn check_source(&mut self, path: &Path) -> Vec<String> {
+        let mut stale = Vec::new();
+        if let Some(watcher) = &mut self.watcher {
+            for changed in watcher.changed() {
+                stale.extend(self.invalidate(&changed));
+            }
+            if self.fingerprints.contains_key(path) {
+                return stale;
+            }
+        }
+        let fingerprint = source::fingerprint(path);
+        match self.fingerprints.get(path) {
+            Some(old) if *old == fingerprint => return stale,
+            Some(_) => stale.extend(self.invalidate(path)),
+            None => {}
+        }
+        if let Some(watcher) = &mut self.watcher {
+            watcher.watch(path);
+        }
+        self.fingerprints.insert(path.to_owned(), fingerprint);
+        stale
+    }
+
+    /// Returns the inode number, listed path and kind of entry `name` in
+    /// directory `parent`.
+    fn entry(&mut self, parent: u64, name: &OsStr) -> Result<(u64, PathBuf, FileType), Errno> {
+        let inodes = self.snapshot.inodes();
+        let ino = inodes.lookup(parent, name).ok_or(ENOENT)?;
+        let inode = inodes.get(ino).ok_or(ENOENT)?;
+        Ok((ino, inodes.source(inode), inode.kind))
+    }
+
+    /// Returns the path within the mount and the listed path for a new entry
+    /// `name` in directory `parent`.
+    fn new_entry(&mut self, parent: u64, name: &OsStr) -> Result<(PathBuf, PathBuf), Errno> {
+        let inodes = self.snapshot.inodes();
+        if inodes.lookup(parent, name).is_some() {
+            return Err(EEXIST);
+        }
+        let path = match inodes.get(parent) {
+            Some(inode) if inode.kind == FileType::Directory => inode.path.join(name),
+            Some(_) => return Err(ENOTDIR),
+            None => return Err(ENOENT),
+        };
+        let source = self.snapshot.source(&path);
+        Ok((path, source))
+    }
+
+    /// Drops everything cached for `path`, returning its disk cache key.
+    fn invalidate(&mut self, path: &Path) -> Option<String> {
+        self.sizes.remove(path);
+        self.stats.remove(path);
+        self.fingerprints.remove(path);
+        self.disk_keys.remove(path)
+    }
+}
+
+impl Inner {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Re-runs the list command if it is due, without holding the state lock
+    /// while it runs, so that requests are served from the previous snapshot
+    /// in the meantime. Until the list command has run once, requests wait
+    /// for it.
+    fn refresh(&self) {
+        let mut state = self.state();
+        loop {
+            if let Some(refresh) = state.snapshot.start_refresh() {
+                drop(state);
+                let inodes = refresh.run(&self.jobs);
+                self.state().snapshot.finish_refresh(refresh, inodes);
+                self.refreshed.notify_all();
+                return;
+            }
+            if state.snapshot.is_listed() {
+                return;
+            }
+            state = self.refreshed.wait(state).unwrap();
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Result<FileAttr, Errno> {
+        let (path, kind) = self.state().inode(ino).ok_or(ENOENT)?;
+        let stat = self.stat(&path)?;
+        let kind = stat.kind.unwrap_or(kind);
+        let size = match (kind, &stat.target) {
+            (FileType::Directory, _) => 0,
+            (FileType::Symlink, Some(target)) => target.as_os_str().len() as u64,
+            _ => match self.draft_size(&path) {
+                Some(size) => size,
+                None => self.file_size(&path)?,
+            },
+        };
+        Ok(attr(ino, kind, size, &stat))
+    }
+
+    /// Returns the size of unflushed changes to `path`, if there are any.
+    fn draft_size(&self, path: &Path) -> Option<u64> {
+        let drafts = self.state().handles.drafts(path);
+        drafts.iter().find_map(|draft| {
+            let draft = draft.lock().unwrap();
+            if draft.dirty {
+                Some(draft.content().len() as u64)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Locks `draft`, first loading the current content of its file into it
+    /// if that hasn't happened yet.
+    ///
+    /// The draft isn't locked while the transform runs, since the state
+    /// lock is taken while drafts are locked.
+    fn load<'a>(&self, draft: &'a Mutex<Draft>) -> Result<MutexGuard<'a, Draft>, Errno> {
+        let path = {
+            let draft = draft.lock().unwrap();
+            if draft.is_loaded() {
+                return Ok(draft);
+            }
+            draft.path.clone()
+        };
+        let mut data = Vec::new();
+        self.output(&path)?.write_to(&mut data).map_err(|e| {
+            warn!("Failed to read transform output for {:?}: {}", path, e);
+            EIO
+        })?;
+        let mut draft = draft.lock().unwrap();
+        // another request may have loaded or truncated it in the meantime
+        if !draft.is_loaded() {
+            draft.data = Some(data);
+        }
+        Ok(draft)
+    }
+
+    /// Changes the size of a draft, loading it first unless everything is
+    /// cut off.
+    fn resize(&self, draft: &Mutex<Draft>, size: u64) -> Result<(), Errno> {
+        let mut draft = match size {
+            0 => draft.lock().unwrap(),
+            _ => self.load(draft)?,
+        };
+        draft.truncate(size)
+    }
+
+    /// Pipes the new content of a file to the write command, unless it was
+    /// already given the latest changes.
+    ///
+    /// The draft isn't locked while the command runs, since the state lock
+    /// is taken while drafts are locked.
+    fn write_back(&self, draft: &Mutex<Draft>) -> Result<(), Errno> {
+        let (command, path, data) = {
+            let mut draft = draft.lock().unwrap();
+            if !draft.dirty {
+                return Ok(());
+            }
+            let command = self.write.as_ref().ok_or(EROFS)?;
+            draft.dirty = false;
+            (command, draft.path.clone(), draft.content().to_vec())
+        };
+        let written = {
+            let _job = self.jobs.acquire();
+            self.errors
+                .run_with_stdin("Write", command, &path, data, self.timeout)
+        };
+        if let Err(errno) = written {
+            draft.lock().unwrap().dirty = true;
+            return Err(errno);
+        }
+        self.forget(&path);
+        Ok(())
+    }
+
+    /// Changes the size of `path` for `truncate(2)` without a file handle,
+    /// through the files which have it open for writing if there are any.
+    fn truncate(&self, path: &Path, size: u64) -> Result<(), Errno> {
+        let drafts = self.state().handles.drafts(path);
+        for draft in &drafts {
+            self.resize(draft, size)?;
+        }
+        if drafts.is_empty() {
+            let draft = Mutex::new(Draft::new(path.to_owned(), self.max_draft));
+            self.resize(&draft, size)?;
+            self.write_back(&draft)?;
+        }
+        Ok(())
+    }
+
+    /// Drops everything cached for `path` after it was changed.
+    fn forget(&self, path: &Path) {
+        let stale = self.state().invalidate(path);
+        if let (Some(key), Some(disk_cache)) = (stale, &self.disk_cache) {
+            disk_cache.lock().unwrap().remove(&key);
+        }
+    }
+
+    /// Returns the metadata of `path`: what the stat command prints for it,
+    /// falling back to what the list command gave.
+    ///
+    /// The stat command isn't run for directories.
+    fn stat(&self, path: &Path) -> Result<Stat, Errno> {
+        let listed = {
+            let state = self.state();
+            if let Some(stat) = state.stats.get(path) {
+                return Ok(stat.clone());
+            }
+            state
+                .snapshot
+                .inodes()
+                .find(path)
+                .map(|inode| (inode.kind, inode.stat.clone()))
+        };
+        let (kind, listed) = listed.ok_or(ENOENT)?;
+        let command = match &self.stat {
+            Some(command) if kind != FileType::Directory => command,
+            _ => return Ok(listed),
+        };
+        self.check_source(path);
+        let stdout = {
+            let _job = self.jobs.acquire();
+            self.errors.run("Stat", command, path, self.timeout)?
+        };
+        let stat = stat::parse_stat(&stdout)
+            .map_err(|e| {
+                warn!(
+                    "Stat command printed invalid metadata for {:?}: {}",
+                    path, e
+                );
+                EIO
+            })?
+            .or(listed);
+        self.state().stats.insert(path.to_owned(), stat.clone());
+        Ok(stat)
+    }
+
+    fn check_source(&self, path: &Path) {
+        if !self.source_files {
+            return;
+        }
+        let stale = self.state().check_source(path);
+        if let Some(disk_cache) = &self.disk_cache {
+            let mut disk_cache = disk_cache.lock().unwrap();
+            for key in stale {
+                disk_cache.remove(&key);
+            }
+        }
+    }
+
+    fn file_size(&self, path: &Path) -> Result<u64, Errno> {
+        self.check_source(path);
+        let strategy = self.rules.get(path).size_strategy;
+        if strategy == SizeStrategy::Zero {
+            return Ok(0);
+        }
+        if let Some(size) = self.state().sizes.get(path) {
+            return Ok(*size);
+        }
+        let size = match (strategy, &self.size) {
+            (SizeStrategy::Transform, _) => self.output(path)?.len(),
+            (_, Some(_)) => self.size_command(path)?,
+            (_, None) => match self.stat(path)?.size {
+                Some(size) => size,
+                // the stat command may leave out the size
+                None => self.output(path)?.len(),
+            },
+        };
+        self.state().sizes.insert(path.to_owned(), size);
+        Ok(size)
+    }
+
+    /// Returns the transform output for `path`. Only its size is kept, so
+    /// that listing a large tree doesn't hold every output in memory.
+    fn output(&self, path: &Path) -> Result<Arc<Buffer>, Errno> {
+        self.check_source(path);
+        self.transforms.run(&path.to_owned(), || {
+            self.cached_transform(path).map(Arc::new)
+        })
+    }
+
+    fn size_command(&self, item: &Path) -> Result<u64, Errno> {
+        let command = self
+            .size
+            .as_ref()
+            .expect("--size-strategy command requires --size.");
+        let stdout = {
+            let _job = self.jobs.acquire();
+            self.errors.run("Size", command, item, self.timeout)?
+        };
+        size::parse_size(&stdout).ok_or_else(|| {
+            warn!("Size command printed no size for {:?}", item);
+            EIO
+        })
+    }
+
+    /// Starts streaming the transform output for `path`, unless it is in
+    /// the disk cache or already being streamed for another open file.
+    fn stream(self: &Arc<Self>, path: &Path) -> Result<Contents, Errno> {
+        self.check_source(path);
+        let promised = self.promised_size(path)?;
+        let key = self.disk_key(path)?;
+        if let Some(data) = key.as_ref().and_then(|key| self.cache_get(key)) {
+            if promised.is_none() {
+                return Ok(Contents::Complete(Arc::new(data)));
+            }
+            // go through a finished spool so that reads are fitted to the size
+            let spool = Spool::new(data, promised);
+            spool.finish(Ok(()));
+            return Ok(Contents::Streaming(Arc::new(spool)));
+        }
+        let spool = {
+            let mut state = self.state();
+            if let Some(spool) = state.spools.get(path) {
+                return Ok(Contents::Streaming(spool.clone()));
+            }
+            let spool = Arc::new(Spool::new(Buffer::new(&self.memory), promised));
+            state.spools.insert(path.to_owned(), spool.clone());
+            spool
+        };
+        let inner = self.clone();
+        let path = path.to_owned();
+        let filling = spool.clone();
+        thread::spawn(move || inner.fill(&path, &filling, key));
+        Ok(Contents::Streaming(spool))
+    }
+
+    fn fill(&self, path: &Path, spool: &Spool, key: Option<String>) {
+        let transform = &self.rules.get(path).transform;
+        let result = {
+            let _job = self.jobs.acquire();
+            self.errors
+                .stream("Transform", transform, path, self.timeout, |chunk| {
+                    spool.push(chunk)
+                })
+        };
+        spool.finish(result);
+        self.state().spools.remove(path);
+        let buffer = match spool.wait() {
+            Ok(buffer) => buffer,
+            Err(_) => return,
+        };
+        match spool.promised() {
+            Some(promised) if promised != buffer.len() => warn!(
+                "Transform produced {} bytes for {:?} but the size command reported {}, \
+                 padding or truncating reads to match",
+                buffer.len(),
+                path,
+                promised
+            ),
+            _ => {}
+        }
+        if let Some(key) = key {
+            self.cache_put(&key, &buffer);
+        }
+    }
+
+    /// Runs the transform, going through the disk cache if there is one.
+    fn cached_transform(&self, item: &Path) -> Result<Buffer, Errno> {
+        let key = match self.disk_key(item)? {
+            Some(key) => key,
+            None => return self.transform(item),
+        };
+        if let Some(data) = self.cache_get(&key) {
+            return Ok(data);
+        }
+        let data = self.transform(item)?;
+        self.cache_put(&key, &data);
+        Ok(data)
+    }
+
+    /// Returns the disk cache key for `item`, if there is a disk cache. The
+    /// key is remembered, so that whatever is cached under it can be dropped
+    /// once `item` is changed through the mount.
+    fn disk_key(&self, item: &Path) -> Result<Option<String>, Errno> {
+        if self.disk_cache.is_none() {
+            return Ok(None);
+        }
+        let fingerprint = self.source_fingerprint(item)?;
+        let transform = &self.rules.get(item).transform;
+        let key = DiskCache::key(item, transform, &fingerprint);
+        self.state().disk_keys.insert(item.to_owned(), key.clone());
+        Ok(Some(key))
+    }
+
+    fn cache_get(&self, key: &str) -> Option<Buffer> {
+        let (file, len) = self.disk_cache.as_ref()?.lock().unwrap().get(key)?;
+        Some(Buffer::from_file(&self.memory, file, len))
+    }
+
+    fn cache_put(&self, key: &str, data: &Buffer) {
+        if let Some(disk_cache) = &self.disk_cache {
+            disk_cache.lock().unwrap().put(key, data);
+        }
+    }
+
+    fn source_fingerprint(&self, item: &Path) -> Result<Vec<u8>, Errno> {
+        let mut fingerprint = if self.source_files {
+            source::fingerprint(item)
+        } else {
+            Vec::new()
+        };
+        if let Some(command) = &self.fingerprint {
+            let _job = self.jobs.acquire();
+            fingerprint.extend(
+                self.errors
+                    .run("Fingerprint", command, item, self.timeout)?,
+            );
+        }
+        Ok(fingerprint)
+    }
+
+    /// Returns the size reported for `path` if it is known without running
+    /// the transform, which reads are then fitted to.
+    fn promised_size(&self, path: &Path) -> Result<Option<u64>, Errno> {
+        match self.rules.get(path).size_strategy {
+            SizeStrategy::Command => self.file_size(path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Runs the transform for just the bytes covered by a read, which it
+    /// gets as `$OFFSET` and `$LENGTH`.
+    fn transform_range(&self, item: &Path, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+        let offset_var = offset.max(0).to_string();
+        let length_var = size.to_string();
+        let env = [
+            ("OFFSET", OsStr::new(&offset_var)),
+            ("LENGTH", OsStr::new(&length_var)),
+        ];
+        let transform = &self.rules.get(item).transform;
+        // before taking a job, since it may run the size or stat command
+        let promised = self.promised_size(item)?;
+        let mut data = {
+            let _job = self.jobs.acquire();
+            self.errors
+                .run_with_env("Transform", transform, item, &env, self.timeout)?
+        };
+        match promised {
+            Some(promised) => {
+                let produced = data.len();
+                size::fit(&mut data, offset, size, promised);
+                if data.len() != produced {
+                    warn!(
+                        "Transform produced {} bytes at offset {} of {:?} where {} were expected",
+                        produced,
+                        offset,
+                        item,
+                        data.len()
+                    );
+                }
+            }
+            None => data.truncate(size as usize),
+        }
+        Ok(data)
+    }
+
+    fn transform(&self, item: &Path) -> Result<Buffer, Errno> {
+        let transform = &self.rules.get(item).transform;
+        let _job = self.jobs.acquire();
+        let mut buffer = Buffer::new(&self.memory);
+        let mut spooled = Ok(());
+        self.errors
+            .stream("Transform", transform, item, self.timeout, |chunk| {
+                if spooled.is_ok() {
+                    spooled = buffer.push(chunk);
+                }
+            })?;
+        spooled.map_err(|e| {
+            warn!("Failed to spool transform output for {:?}: {}", item, e);
+            EIO
+        })?;
+        Ok(buffer)
+    }
+}
+
+impl Filesystem for ShellFS {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        info!("Calling lookup: {} {:?}", parent, name);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let ino = inner.state().snapshot.inodes().lookup(parent, &name);
+            match ino.ok_or(ENOENT).and_then(|ino| inner.attr(ino)) {
+                Ok(attr) => reply.entry(&TTL, &attr, 0),
+                Err(errno) => reply.error(errno),
+            }
+        });
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        info!("Calling getattr: {}", ino);
+        self.spawn(move |inner| match inner.attr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(errno) => reply.error(errno),
+        });
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        info!("Calling readlink: {}", ino);
+        self.spawn(move |inner| {
+            let path = match inner.state().inode(ino) {
+                Some((path, _)) => path,
+                None => return reply.error(ENOENT),
+            };
+            match inner.stat(&path) {
+                Ok(Stat {
+                    kind: Some(FileType::Symlink),
+                    target: Some(target),
+                    ..
+                }) => reply.data(target.as_os_str().as_bytes()),
+                Ok(_) => reply.error(EINVAL),
+                Err(errno) => reply.error(errno),
+            }
+        });
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        info!("Calling getxattr: {} {:?} {}", ino, name, size);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let path = match inner.state().inode(ino) {
+                Some((path, _)) => path,
+                None => return reply.error(ENOENT),
+            };
+            let stat = match inner.stat(&path) {
+                Ok(stat) => stat,
+                Err(errno) => return reply.error(errno),
+            };
+            let value = match name.to_str().and_then(|name| stat.xattrs.get(name)) {
+                Some(value) => value,
+                None => return reply.error(ENODATA),
+            };
+            reply_xattr(reply, size, value);
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        info!("Calling listxattr: {} {}", ino, size);
+        self.spawn(move |inner| {
+            let path = match inner.state().inode(ino) {
+                Some((path, _)) => path,
+                None => return reply.error(ENOENT),
+            };
+            let stat = match inner.stat(&path) {
+                Ok(stat) => stat,
+                Err(errno) => return reply.error(errno),
+            };
+            let mut names = Vec::new();
+            for name in stat.xattrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+            reply_xattr(reply, size, &names);
+        });
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        info!("Calling open: {} {}", ino, flags);
+        self.spawn(move |inner| {
+            let path = match inner.state().inode(ino) {
+                Some((_, FileType::Directory)) => return reply.error(EISDIR),
+                Some((path, _)) => path,
+                None => return reply.error(ENOENT),
+            };
+            let size_strategy = inner.rules.get(&path).size_strategy;
+            let writing = flags as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+            if writing && inner.write.is_none() {
+                return reply.error(EROFS);
+            }
+            let contents = match size_strategy {
+                // the kernel truncates files opened with O_TRUNC through a
+                // separate setattr, so the draft is only loaded once needed
+                _ if writing => {
+                    let draft = Draft::new(path, inner.max_draft);
+                    Ok(Contents::Writing(Arc::new(Mutex::new(draft))))
+                }
+                _ if inner.range_transform => Ok(Contents::Range(path)),
+                // the transform may not give the same output it was measured
+                // by, so report the size of what is read from now on
+                SizeStrategy::Transform => inner.output(&path).map(|data| {
+                    inner.state().sizes.insert(path.clone(), data.len());
+                    Contents::Complete(data)
+                }),
+                // the size doesn't depend on the output, so reads can start
+                // before the transform is done
+                _ => inner.stream(&path),
+            };
+            let contents = match contents {
+                Ok(contents) => contents,
+                Err(errno) => return reply.error(errno),
+            };
+            let fh = inner.state().handles.insert(OpenFile { contents });
+            let open_flags = match size_strategy {
+                SizeStrategy::Zero => fuse::consts::FOPEN_DIRECT_IO,
+                _ => 0,
+            };
+            reply.opened(fh, open_flags);
+        });
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        reply: ReplyData,
+    ) {
+        info!("Calling read: {} {} {} {}", ino, fh, offset, size);
+        let contents = self
+            .inner
+            .state()
+            .handles
+            .get(fh)
+            .map(|file| file.contents.clone());
+        match contents {
+            Some(Contents::Complete(buffer)) => match buffer.read(offset, size) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    warn!("Failed to read transform output: {}", e);
+                    reply.error(EIO)
+                }
+            },
+            Some(Contents::Streaming(spool)) => {
+                self.workers.run(move || match spool.read(offset, size) {
+                    Ok(data) => reply.data(&data),
+                    Err(errno) => reply.error(errno),
+                });
+            }
+            Some(Contents::Range(path)) => {
+                self.spawn(
+                    move |inner| match inner.transform_range(&path, offset, size) {
+                        Ok(data) => reply.data(&data),
+                        Err(errno) => reply.error(errno),
+                    },
+                );
+            }
+            Some(Contents::Writing(draft)) => self.spawn(move |inner| match inner.load(&draft) {
+                Ok(draft) => reply.data(buffer::slice(draft.content(), offset, size)),
+                Err(errno) => reply.error(errno),
+            }),
+            None => reply.error(EBADF),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _flags: u32,
+        reply: ReplyWrite,
+    ) {
+        info!("Calling write: {} {} {} {}", ino, fh, offset, data.len());
+        let contents = self
+            .inner
+            .state()
+            .handles
+            .get(fh)
+            .map(|file| file.contents.clone());
+        match contents {
+            // files created without --write can't be given any content
+            Some(Contents::Writing(_)) if self.inner.write.is_none() => reply.error(EROFS),
+            Some(Contents::Writing(draft)) => {
+                let data = data.to_owned();
+                self.spawn(move |inner| match inner.load(&draft) {
+                    Ok(mut draft) => match draft.write(offset, &data) {
+                        Ok(()) => reply.written(data.len() as u32),
+                        Err(errno) => reply.error(errno),
+                    },
+                    Err(errno) => reply.error(errno),
+                })
+            }
+            _ => reply.error(EBADF),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        info!("Calling flush: {} {}", ino, fh);
+        let contents = self
+            .inner
+            .state()
+            .handles
+            .get(fh)
+            .map(|file| file.contents.clone());
+        match contents {
+            // what close(2) returns, so write-back failures are reported here
+            Some(Contents::Writing(draft)) => {
+                self.spawn(move |inner| match inner.write_back(&draft) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                })
+            }
+            Some(_) => reply.ok(),
+            None => reply.error(EBADF),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn setattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<SystemTime>,
+        _mtime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        info!("Calling setattr: {} {:?} {:?}", ino, size, fh);
+        let draft = fh.and_then(|fh| match self.inner.state().handles.get(fh) {
+            Some(OpenFile {
+                contents: Contents::Writing(draft),
+            }) => Some(draft.clone()),
+            _ => None,
+        });
+        self.spawn(move |inner| {
+            // only changes of size are supported, anything else is ignored
+            if let Some(size) = size {
+                let truncated = match (&draft, inner.write.is_some()) {
+                    (_, false) => Err(EROFS),
+                    (Some(draft), true) => inner.resize(draft, size),
+                    (None, true) => match inner.state().inode(ino) {
+                        Some((path, _)) => inner.truncate(&path, size),
+                        None => Err(ENOENT),
+                    },
+                };
+                if let Err(errno) = truncated {
+                    return reply.error(errno);
+                }
+            }
+            match inner.attr(ino) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(errno) => reply.error(errno),
+            }
+        });
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!("Calling release: {} {}", ino, fh);
+        let file = self.inner.state().handles.remove(fh);
+        match file {
+            // normally flushed already, unless the kernel skipped the flush
+            Some(OpenFile {
+                contents: Contents::Writing(draft),
+            }) => self.spawn(move |inner| {
+                if let Err(errno) = inner.write_back(&draft) {
+                    warn!("Changes to inode {} were lost: errno {}", ino, errno);
+                }
+                reply.ok();
+            }),
+            _ => reply.ok(),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        flags: u32,
+        reply: ReplyCreate,
+    ) {
+        info!("Calling create: {} {:?} {}", parent, name, flags);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let command = match &inner.create {
+                Some(command) => command,
+                None => return reply.error(EROFS),
+            };
+            let (path, source) = match inner.state().new_entry(parent, &name) {
+                Ok(entry) => entry,
+                Err(errno) => return reply.error(errno),
+            };
+            {
+                let _job = inner.jobs.acquire();
+                if let Err(errno) = inner.errors.run("Create", command, &source, inner.timeout) {
+                    return reply.error(errno);
+                }
+            }
+            let inserted = {
+                let mut state = inner.state();
+                state.snapshot.note_change();
+                state.snapshot.insert(&path, &source, FileType::RegularFile)
+            };
+            let ino = match inserted {
+                Ok(ino) => ino,
+                Err(e) => {
+                    warn!("Created {:?}, but can't show it: {}", source, e);
+                    return reply.error(EIO);
+                }
+            };
+            inner.forget(&source);
+            let mut draft = Draft::new(source, inner.max_draft);
+            // there's nothing to load for a new file
+            draft.data = Some(Vec::new());
+            let fh = inner.state().handles.insert(OpenFile {
+                contents: Contents::Writing(Arc::new(Mutex::new(draft))),
+            });
+            let attr = attr(ino, FileType::RegularFile, 0, &Stat::default());
+            reply.created(&TTL, &attr, 0, fh, 0);
+        });
+    }
+
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("Calling unlink: {} {:?}", parent, name);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let command = match &inner.unlink {
+                Some(command) => command,
+                None => return reply.error(EROFS),
+            };
+            let (ino, source) = match inner.state().entry(parent, &name) {
+                Ok((_, _, FileType::Directory)) => return reply.error(EISDIR),
+                Ok((ino, source, _)) => (ino, source),
+                Err(errno) => return reply.error(errno),
+            };
+            {
+                let _job = inner.jobs.acquire();
+                if let Err(errno) = inner.errors.run("Unlink", command, &source, inner.timeout) {
+                    return reply.error(errno);
+                }
+            }
+            {
+                let mut state = inner.state();
+                state.snapshot.remove(ino);
+                state.snapshot.note_change();
+            }
+            inner.forget(&source);
+            reply.ok();
+        });
+    }
+
+    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        info!("Calling mkdir: {} {:?}", parent, name);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let command = match &inner.mkdir {
+                Some(command) => command,
+                None => return reply.error(EROFS),
+            };
+            let (path, source) = match inner.state().new_entry(parent, &name) {
+                Ok(entry) => entry,
+                Err(errno) => return reply.error(errno),
+            };
+            {
+                let _job = inner.jobs.acquire();
+                if let Err(errno) = inner.errors.run("Mkdir", command, &source, inner.timeout) {
+                    return reply.error(errno);
+                }
+            }
+            let inserted = {
+                let mut state = inner.state();
+                state.snapshot.note_change();
+                state.snapshot.insert(&path, &source, FileType::Directory)
+            };
+            inner.forget(&source);
+            match inserted {
+                Ok(ino) => {
+                    let attr = attr(ino, FileType::Directory, 0, &Stat::default());
+                    reply.entry(&TTL, &attr, 0);
+                }
+                Err(e) => {
+                    warn!("Created {:?}, but can't show it: {}", source, e);
+                    reply.error(EIO);
+                }
+            }
+        });
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        info!("Calling rmdir: {} {:?}", parent, name);
+        let name = name.to_owned();
+        self.spawn(move |inner| {
+            let command = match &inner.rmdir {
+                Some(command) => command,
+                None => return reply.error(EROFS),
+            };
+            let removed = {
+                let mut state = inner.state();
+                state.entry(parent, &name).and_then(|(ino, source, kind)| {
+                    if kind != FileType::Directory {
+                        return Err(ENOTDIR);
+                    }
+                    if state.snapshot.inodes().children(ino).next().is_some() {
+                        return Err(ENOTEMPTY);
+                    }
+                    Ok((ino, source))
+                })
+            };
+            let (ino, source) = match removed {
+                Ok(removed) => removed,
+                Err(errno) => return reply.error(errno),
+            };
+            {
+                let _job = inner.jobs.acquire();
+                if let Err(errno) = inner.errors.run("Rmdir", command, &source, inner.timeout) {
+                    return reply.error(errno);
+                }
+            }
+            {
+                let mut state = inner.state();
+                state.snapshot.remove(ino);
+                state.snapshot.note_change();
+            }
+            inner.forget(&source);
+            reply.ok();
+        });
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEmpty,
+    ) {
+        info!(
+            "Calling rename: {} {:?} {} {:?}",
+            parent, name, newparent, newname
+        );
+        let (name, newname) = (name.to_owned(), newname.to_owned());
+        self.spawn(move |inner| {
+            let command = match &inner.rename {
+                Some(command) => command,
+                None => return reply.error(EROFS),
+            };
+            // an existing entry is replaced, as long as rename(2) would
+            let renamed = {
+                let mut state = inner.state();
+                state.entry(parent, &name).and_then(|(ino, from, kind)| {
+                    let (path, to, replaced) = match state.entry(newparent, &newname) {
+                        Ok((target, to, target_kind)) => {
+                            let inodes = state.snapshot.inodes();
+                            match (kind, target_kind) {
+                                (FileType::Directory, FileType::Directory)
+                                    if inodes.children(target).next().is_some() =>
+                                {
+                                    return Err(ENOTEMPTY)
+                                }
+                                (FileType::Directory, FileType::Directory) => {}
+                                (FileType::Directory, _) => return Err(ENOTDIR),
+                                (_, FileType::Directory) => return Err(EISDIR),
+                                _ => {}
+                            }
+                            let path = inodes.get(target).ok_or(ENOENT)?.path.clone();
+                            (path, to, Some(target))
+                        }
+                        Err(_) => {
+                            let (path, to) = state.new_entry(newparent, &newname)?;
+                            (path, to, None)
+                        }
+                    };
+                    Ok((ino, from, path, to, replaced))
+                })
+            };
+            let (ino, from, path, to, replaced) = match renamed {
+                Ok(renamed) => renamed,
+                Err(errno) => return reply.error(errno),
+            };
+            // a file another rule applies to would be served differently,
+            // so let `mv` copy it through the mount instead
+            if !std::ptr::eq(inner.rules.get(&from), inner.rules.get(&to)) {
+                return reply.error(EXDEV);
+            }
+            {
+                let _job = inner.jobs.acquire();
+                let env = [("FROM", from.as_os_str()), ("TO", to.as_os_str())];
+                let renamed =
+                    inner
+                        .errors
+                        .run_with_env("Rename", command, &from, &env, inner.timeout);
+                if let Err(errno) = renamed {
+                    return reply.error(errno);
+                }
+            }
+            {
+                let mut state = inner.state();
+                if let Some(replaced) = replaced {
+                    state.snapshot.remove(replaced);
+                }
+                state.snapshot.rename(ino, newparent, &path, &to);
+                state.snapshot.note_change();
+                state.handles.rename(&from, &to);
+            }
+            inner.forget(&from);
+            inner.forget(&to);
+            reply.ok();
+        });
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        info!("Calling readdir: {} {} {}", ino, fh, offset);
+        self.spawn(move |inner| {
+            let state = inner.state();
+            let items = state.snapshot.inodes();
+
+            let parent = match items.get(ino) {
+                Some(inode) if inode.parent_inode == 0 => ino,
+                Some(inode) => inode.parent_inode,
+                None => {
+                    reply.error(ENOENT);
+                    return;
+                }
+            };
+
+            let dots = vec![
+                (ino, FileType::Directory, OsStr::new(".")),
+                (parent, FileType::Directory, OsStr::new("..")),
+            ];
+            let children = items.children(ino).filter_map(|(name, child)| {
+                items.get(child).map(|inode| (child, inode.kind, name))
+            });
+
+            for (i, entry) in dots
+                .into_iter()
+                .chain(children)
+                .enumerate()
+                .skip(offset as usize)
+            {
+                // i + 1 means the index of the next entry
+                if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+                    // the reply buffer is full
+                    break;
+                }
+            }
+            reply.ok();
+        });
+    }
+}
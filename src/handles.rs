@@ -0,0 +1,141 @@
+use crate::buffer::Buffer;
+use crate::command::Errno;
+use crate::inode;
+use crate::spool::Spool;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// What reads of an open file are served from.
+#[derive(Clone)]
+pub enum Contents {
+    /// The complete transform output.
+    Complete(Arc<Buffer>),
+    /// The output of a transform which may still be running.
+    Streaming(Arc<Spool>),
+    /// Nothing; each read runs the transform for just the range it covers.
+    Range(PathBuf),
+    /// The new content of a file opened for writing.
+    Writing(Arc<Mutex<Draft>>),
+}
+
+/// The content of a file opened for writing, which is handed to the write
+/// command once the file is flushed.
+pub struct Draft {
+    /// The path the write command gets as `$INPUT`.
+    pub path: PathBuf,
+    /// The new content; `None` until the current content of the file is
+    /// needed, so that a file which is overwritten isn't transformed first.
+    pub data: Option<Vec<u8>>,
+    /// Set while there are changes the write command hasn't been given.
+    pub dirty: bool,
+    /// The largest size the draft may grow to, since it is kept in memory.
+    limit: u64,
+}
+
+impl Draft {
+    /// Starts a draft of `path`, loaded once it is read or changed.
+    pub fn new(path: PathBuf, limit: u64) -> Self {
+        Draft {
+            path,
+            data: None,
+            dirty: false,
+            limit,
+        }
+    }
+
+    /// Whether the draft is ready for reads and writes.
+    pub fn is_loaded(&self) -> bool {
+        self.data.is_some()
+    }
+
+    /// The content of the draft, empty until it is loaded.
+    pub fn content(&self) -> &[u8] {
+        self.data.as_deref().unwrap_or_default()
+    }
+
+    /// Writes `data` at `offset`; the draft has to be loaded first.
+    pub fn write(&mut self, offset: i64, data: &[u8]) -> Result<(), Errno> {
+        let offset = offset.max(0) as u64;
+        let end = offset.saturating_add(data.len() as u64);
+        if end > self.limit {
+            return Err(libc::EFBIG);
+        }
+        let (offset, end) = (offset as usize, end as usize);
+        let content = self.data.get_or_insert_with(Vec::new);
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset..end].copy_from_slice(data);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Cuts off or extends the draft to `size`; the draft has to be loaded
+    /// first, unless `size` is 0.
+    pub fn truncate(&mut self, size: u64) -> Result<(), Errno> {
+        if size > self.limit {
+            return Err(libc::EFBIG);
+        }
+        self.data
+            .get_or_insert_with(Vec::new)
+            .resize(size as usize, 0);
+        self.dirty = true;
+        Ok(())
+    }
+}
+
+/// Transform output captured when a file was opened.
+pub struct OpenFile {
+    pub contents: Contents,
+}
+
+/// Open files, keyed by the file handle handed to the kernel.
+#[derive(Default)]
+pub struct Handles {
+    next_fh: u64,
+    open: HashMap<u64, OpenFile>,
+}
+
+impl Handles {
+    pub fn insert(&mut self, file: OpenFile) -> u64 {
+        self.next_fh += 1;
+        self.open.insert(self.next_fh, file);
+        self.next_fh
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&OpenFile> {
+        self.open.get(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<OpenFile> {
+        self.open.remove(&fh)
+    }
+
+    /// Returns the drafts of the handles which have `path` open for
+    /// writing.
+    pub fn drafts(&self, path: &Path) -> Vec<Arc<Mutex<Draft>>> {
+        self.open
+            .values()
+            .filter_map(|file| match &file.contents {
+                Contents::Writing(draft) => Some(draft),
+                _ => None,
+            })
+            .filter(|draft| draft.lock().unwrap().path == path)
+            .cloned()
+            .collect()
+    }
+
+    /// Points the handles writing to `from`, or to a file below it, at the
+    /// path it was renamed to.
+    pub fn rename(&self, from: &Path, to: &Path) {
+        for file in self.open.values() {
+            if let Contents::Writing(draft) = &file.contents {
+                let mut draft = draft.lock().unwrap();
+                if let Some(path) = inode::moved(&draft.path, from, to) {
+                    draft.path = path;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,404 @@
+use crate::cache::fnv1a;
+use crate::stat::Stat;
+use fuse::FileType;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// The inode number of the mount root, fixed by FUSE.
+pub const ROOT: u64 = 1;
+
+/// Returns where `path` ends up when `from` is renamed to `to`, if it is
+/// `from` or below it.
+pub fn moved(path: &Path, from: &Path, to: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(from).ok()?;
+    if rest.as_os_str().is_empty() {
+        // `to.join("")` would add a trailing `/`
+        Some(to.to_owned())
+    } else {
+        Some(to.join(rest))
+    }
+}
+
+#[derive(Debug)]
+pub struct Inode {
+    /// The path within the mount.
+    pub path: PathBuf,
+    /// The path printed by the list command; unset for directories which
+    /// are only there as the parent of other paths.
+    pub source: Option<PathBuf>,
+    pub kind: FileType,
+    pub parent_inode: u64,
+    /// Metadata given by the list command.
+    pub stat: Stat,
+}
+
+/// Assigns inode numbers to paths.
+///
+/// Numbers are derived from a hash of the path, so they stay the same across
+/// list refreshes and remounts. Colliding paths are moved to the next free
+/// number, and a path keeps its number for the lifetime of the mount even
+/// if it disappears from the list in the meantime.
+#[derive(Default)]
+pub struct InodeNumbers {
+    assigned: HashMap<PathBuf, u64>,
+    taken: HashMap<u64, PathBuf>,
+}
+
+impl InodeNumbers {
+    pub fn get(&mut self, path: &Path) -> u64 {
+        if path == Path::new("") {
+            return ROOT;
+        }
+        if let Some(ino) = self.assigned.get(path) {
+            return *ino;
+        }
+        let mut ino = fnv1a(&[path.as_os_str().as_bytes()]);
+        while ino <= ROOT || self.taken.contains_key(&ino) {
+            ino = ino.wrapping_add(1);
+        }
+        self.assigned.insert(path.to_owned(), ino);
+        self.taken.insert(ino, path.to_owned());
+        ino
+    }
+
+    /// Gives the number of `from` to `to`, for a path which was renamed.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        if let Some(ino) = self.assigned.remove(from) {
+            self.assigned.insert(to.to_owned(), ino);
+            self.taken.insert(ino, to.to_owned());
+        }
+    }
+}
+
+/// The inodes of one snapshot, indexed by number, by path, by source path
+/// and by parent directory.
+pub struct InodeTable {
+    /// The directory listed paths were made relative to, which the paths
+    /// of unlisted directories are joined to.
+    base: Option<PathBuf>,
+    inodes: HashMap<u64, Inode>,
+    by_path: HashMap<PathBuf, u64>,
+    by_source: HashMap<PathBuf, u64>,
+    children: HashMap<u64, BTreeMap<OsString, u64>>,
+}
+
+impl InodeTable {
+    pub fn new(base: Option<PathBuf>) -> Self {
+        let mut table = InodeTable {
+            base,
+            inodes: HashMap::new(),
+            by_path: HashMap::new(),
+            by_source: HashMap::new(),
+            children: HashMap::new(),
+        };
+        table.inodes.insert(
+            ROOT,
+            Inode {
+                path: PathBuf::from(""),
+                source: None,
+                kind: FileType::Directory,
+                parent_inode: 0,
+                stat: Stat::default(),
+            },
+        );
+        table.by_path.insert(PathBuf::from(""), ROOT);
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+
+    pub fn get(&self, ino: u64) -> Option<&Inode> {
+        self.inodes.get(&ino)
+    }
+
+    /// Returns the path commands get as `$INPUT` for `inode`: the path the
+    /// list command printed, or for directories which are only there as the
+    /// parent of other paths, the path they would be listed as.
+    pub fn source(&self, inode: &Inode) -> PathBuf {
+        match (&inode.source, &self.base) {
+            (Some(source), _) => source.clone(),
+            (None, Some(base)) => moved(&inode.path, Path::new(""), base).unwrap_or_default(),
+            (None, None) => inode.path.clone(),
+        }
+    }
+
+    /// Returns the inode whose commands get `source` as `$INPUT`.
+    pub fn find(&self, source: &Path) -> Option<&Inode> {
+        let path = match &self.base {
+            Some(base) => source.strip_prefix(base).ok(),
+            None => Some(source),
+        };
+        let ino = self
+            .by_source
+            .get(source)
+            .or_else(|| self.by_path.get(path?))?;
+        self.inodes.get(ino)
+    }
+
+    pub fn lookup(&self, parent: u64, name: &OsStr) -> Option<u64> {
+        self.children.get(&parent)?.get(name).copied()
+    }
+
+    /// Returns the entries of directory `ino`, sorted by name.
+    pub fn children(&self, ino: u64) -> impl Iterator<Item = (&OsStr, u64)> {
+        self.children
+            .get(&ino)
+            .into_iter()
+            .flat_map(|children| children.iter())
+            .map(|(name, ino)| (name.as_os_str(), *ino))
+    }
+
+    /// Inserts `path` along with any missing parent directories, returning
+    /// its inode number.
+    pub fn insert_path(&mut self, numbers: &mut InodeNumbers, path: &Path, kind: FileType) -> u64 {
+        if let Some(ino) = self.by_path.get(path) {
+            return *ino;
+        }
+        let parent_inode = match path.parent() {
+            Some(parent) => self.insert_path(numbers, parent, FileType::Directory),
+            None => ROOT,
+        };
+        let name = match path.file_name() {
+            Some(name) => name.to_owned(),
+            // `/` and `.` are the root itself
+            None => return ROOT,
+        };
+
+        let ino = numbers.get(path);
+        self.inodes.insert(
+            ino,
+            Inode {
+                path: path.to_owned(),
+                source: None,
+                kind,
+                parent_inode,
+                stat: Stat::default(),
+            },
+        );
+        self.by_path.insert(path.to_owned(), ino);
+        self.children
+            .entry(parent_inode)
+            .or_default()
+            .insert(name, ino);
+        ino
+    }
+
+    /// Inserts a path printed by the list command at `path`, like
+    /// `insert_path`, along with its source path and metadata. Directories
+    /// which were already added as the parent of another path get them
+    /// too.
+    ///
+    /// Fails if another source path was already inserted at `path`, or if
+    /// `path` is already there with a different kind.
+    pub fn insert_entry(
+        &mut self,
+        numbers: &mut InodeNumbers,
+        path: &Path,
+        source: &Path,
+        kind: FileType,
+        stat: Stat,
+    ) -> Result<u64, String> {
+        if let Some(existing) = self.by_path.get(path).and_then(|ino| self.inodes.get(ino)) {
+            match &existing.source {
+                Some(other) if other != source => {
+                    return Err(format!("{:?} is already listed as {:?}", other, path))
+                }
+                _ if existing.kind == FileType::Directory && kind != FileType::Directory => {
+                    return Err(format!("there is a directory at {:?}", path))
+                }
+                _ if existing.kind != kind => {
+                    return Err(format!("{:?} is already listed with another type", path))
+                }
+                _ => {}
+            }
+        }
+        let ino = self.insert_path(numbers, path, kind);
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.source = Some(source.to_owned());
+            inode.stat = stat;
+        }
+        self.by_source.insert(source.to_owned(), ino);
+        Ok(ino)
+    }
+
+    /// Moves `ino` to `path` in directory `parent`, along with everything
+    /// below it, keeping their inode numbers. Listed paths below `source`
+    /// are moved along with it.
+    pub fn rename(
+        &mut self,
+        numbers: &mut InodeNumbers,
+        ino: u64,
+        parent: u64,
+        path: &Path,
+        source: &Path,
+    ) {
+        let (old_path, old_source) = match self.inodes.get(&ino) {
+            Some(inode) => (inode.path.clone(), self.source(inode)),
+            None => return,
+        };
+        if let (Some(siblings), Some(name)) = (
+            self.children.get_mut(&self.inodes[&ino].parent_inode),
+            old_path.file_name(),
+        ) {
+            siblings.remove(name);
+        }
+        if let Some(name) = path.file_name() {
+            self.children
+                .entry(parent)
+                .or_default()
+                .insert(name.to_owned(), ino);
+        }
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.parent_inode = parent;
+        }
+
+        let mut pending = vec![ino];
+        while let Some(ino) = pending.pop() {
+            pending.extend(self.children(ino).map(|(_, child)| child));
+            let inode = match self.inodes.get_mut(&ino) {
+                Some(inode) => inode,
+                None => continue,
+            };
+            let new_path = match moved(&inode.path, &old_path, path) {
+                Some(new_path) => new_path,
+                None => continue,
+            };
+            self.by_path.remove(&inode.path);
+            self.by_path.insert(new_path.clone(), ino);
+            numbers.rename(&inode.path, &new_path);
+            inode.path = new_path;
+            if let Some(listed) = &mut inode.source {
+                if let Some(new_source) = moved(listed, &old_source, source) {
+                    self.by_source.remove(listed.as_path());
+                    self.by_source.insert(new_source.clone(), ino);
+                    *listed = new_source;
+                }
+            }
+        }
+    }
+
+    /// Removes `ino` and its entry in the parent directory.
+    pub fn remove(&mut self, ino: u64) -> Option<Inode> {
+        if ino == ROOT {
+            return None;
+        }
+        let inode = self.inodes.remove(&ino)?;
+        self.by_path.remove(&inode.path);
+        if let Some(source) = &inode.source {
+            self.by_source.remove(source);
+        }
+        if let (Some(siblings), Some(name)) = (
+            self.children.get_mut(&inode.parent_inode),
+            inode.path.file_name(),
+        ) {
+            siblings.remove(name);
+        }
+        self.children.remove(&ino);
+        Some(inode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(table: &mut InodeTable, numbers: &mut InodeNumbers, path: &str, source: &str) -> u64 {
+        let (path, source) = (Path::new(path), Path::new(source));
+        table
+            .insert_entry(
+                numbers,
+                path,
+                source,
+                FileType::RegularFile,
+                Stat::default(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn colliding_paths_keep_distinct_numbers() {
+        let mut numbers = InodeNumbers::default();
+        // make `y` hash to the number of `x`, as if the two collided
+        let hash = fnv1a(&[b"x"]);
+        numbers.assigned.insert(PathBuf::from("y"), hash);
+        numbers.taken.insert(hash, PathBuf::from("y"));
+
+        let mut table = InodeTable::new(None);
+        let x = file(&mut table, &mut numbers, "x", "x");
+        let y = file(&mut table, &mut numbers, "y", "y");
+        assert_eq!(y, hash);
+        // `x` moves on to the next free number
+        assert_eq!(x, hash.wrapping_add(1));
+
+        // a refresh listing them the other way round changes nothing
+        let mut table = InodeTable::new(None);
+        assert_eq!(file(&mut table, &mut numbers, "y", "y"), y);
+        assert_eq!(file(&mut table, &mut numbers, "x", "x"), x);
+        assert_eq!(table.lookup(ROOT, OsStr::new("x")), Some(x));
+        assert_eq!(table.lookup(ROOT, OsStr::new("y")), Some(y));
+    }
+
+    #[test]
+    fn rename_file_keeps_inode_number() {
+        let mut numbers = InodeNumbers::default();
+        let mut table = InodeTable::new(None);
+        let ino = file(&mut table, &mut numbers, "a/x", "a/x");
+        let a = table.lookup(ROOT, OsStr::new("a")).unwrap();
+        let b = table.insert_path(&mut numbers, Path::new("b"), FileType::Directory);
+
+        table.rename(&mut numbers, ino, b, Path::new("b/y"), Path::new("b/y"));
+
+        assert_eq!(table.lookup(a, OsStr::new("x")), None);
+        assert_eq!(table.lookup(b, OsStr::new("y")), Some(ino));
+        let inode = table.get(ino).unwrap();
+        assert_eq!(inode.path, Path::new("b/y"));
+        assert_eq!(inode.parent_inode, b);
+        assert_eq!(table.source(inode), Path::new("b/y"));
+        assert!(table.find(Path::new("a/x")).is_none());
+        assert_eq!(
+            table.find(Path::new("b/y")).map(|inode| &inode.path),
+            Some(&inode.path)
+        );
+        // the new path keeps the number when the list is read again
+        assert_eq!(numbers.get(Path::new("b/y")), ino);
+    }
+
+    #[test]
+    fn rename_unlisted_directory_moves_children() {
+        let mut numbers = InodeNumbers::default();
+        let mut table = InodeTable::new(Some(PathBuf::from("/base")));
+        let child = file(&mut table, &mut numbers, "sub/f", "/base/sub/f");
+        let sub = table.lookup(ROOT, OsStr::new("sub")).unwrap();
+        assert_eq!(
+            table.source(table.get(sub).unwrap()),
+            Path::new("/base/sub")
+        );
+        assert_eq!(table.source(table.get(ROOT).unwrap()), Path::new("/base"));
+
+        table.rename(
+            &mut numbers,
+            sub,
+            ROOT,
+            Path::new("new"),
+            Path::new("/base/new"),
+        );
+
+        assert_eq!(table.lookup(ROOT, OsStr::new("sub")), None);
+        assert_eq!(table.lookup(ROOT, OsStr::new("new")), Some(sub));
+        assert_eq!(table.lookup(sub, OsStr::new("f")), Some(child));
+        let inode = table.get(child).unwrap();
+        assert_eq!(inode.path, Path::new("new/f"));
+        assert_eq!(table.source(inode), Path::new("/base/new/f"));
+        assert!(table.find(Path::new("/base/sub/f")).is_none());
+        assert!(table.find(Path::new("/base/new/f")).is_some());
+        assert_eq!(
+            table.find(Path::new("/base/new")).map(|inode| &inode.path),
+            Some(&PathBuf::from("new"))
+        );
+        assert_eq!(numbers.get(Path::new("new/f")), child);
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+/// Limits how many commands run at the same time.
+pub struct Jobs {
+    limit: usize,
+    running: Mutex<usize>,
+    finished: Condvar,
+}
+
+impl Jobs {
+    pub fn new(limit: usize) -> Self {
+        Jobs {
+            limit: limit.max(1),
+            running: Mutex::new(0),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Blocks until fewer than `limit` jobs are running, and counts the
+    /// caller as running until the returned slot is dropped.
+    pub fn acquire(&self) -> Slot<'_> {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.finished.wait(running).unwrap();
+        }
+        *running += 1;
+        Slot(self)
+    }
+}
+
+pub struct Slot<'a>(&'a Jobs);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_one();
+    }
+}
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads running tasks from a bounded queue.
+pub struct Workers {
+    queue: SyncSender<Task>,
+}
+
+impl Workers {
+    pub fn new(threads: usize, queue: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Task>(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let task = match receiver.lock().unwrap().recv() {
+                    Ok(task) => task,
+                    Err(_) => return,
+                };
+                // a panicking task mustn't take the thread down with it
+                let _ = panic::catch_unwind(AssertUnwindSafe(task));
+            });
+        }
+        Workers { queue: sender }
+    }
+
+    /// Queues `task` for the next free thread, blocking while the queue is
+    /// full.
+    pub fn run<F: FnOnce() + Send + 'static>(&self, task: F) {
+        let _ = self.queue.send(Box::new(task));
+    }
+}
+
+struct Flight<V> {
+    result: Mutex<Option<V>>,
+    done: Condvar,
+}
+
+/// Computations in progress, so that concurrent requests for the same key
+/// share one result instead of each starting their own.
+pub struct InFlight<K, V> {
+    flights: Mutex<HashMap<K, Arc<Flight<V>>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> InFlight<K, V> {
+    pub fn new() -> Self {
+        InFlight {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `f`, unless it is already running for `key`, in which case its
+    /// result is waited for instead.
+    pub fn run<F: FnOnce() -> V>(&self, key: &K, f: F) -> V {
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        result: Mutex::new(None),
+                        done: Condvar::new(),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if leader {
+            let result = f();
+            *flight.result.lock().unwrap() = Some(result.clone());
+            self.flights.lock().unwrap().remove(key);
+            flight.done.notify_all();
+            result
+        } else {
+            let mut result = flight.result.lock().unwrap();
+            loop {
+                match &*result {
+                    Some(result) => return result.clone(),
+                    None => result = flight.done.wait(result).unwrap(),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::jobs::Jobs;
+use crate::rule::Rules;
+use crate::stat::{self, Stat};
+use fuse::FileType;
+use log::warn;
+use serde_json::Value;
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How the output of the list command is read.
+///
+/// In all formats but `jsonl`, a path ending in `/` is a directory, which
+/// is shown even if nothing is listed below it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    /// One path per line, without trailing whitespace.
+    Lines,
+    /// One path per line, optionally in double quotes, with C-style escapes
+    /// such as `\n`, `\t`, `\\`, `\"`, `\x1b` or `\033`.
+    Escaped,
+    /// Paths terminated by NUL bytes, as printed by `find -print0`.
+    Null,
+    /// One JSON object per line, with the path and its metadata.
+    Jsonl,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(ListFormat::Lines),
+            "escaped" => Ok(ListFormat::Escaped),
+            "null" => Ok(ListFormat::Null),
+            "jsonl" => Ok(ListFormat::Jsonl),
+            _ => Err(format!(
+                "expected `lines`, `escaped`, `null` or `jsonl`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// A path printed by the list command.
+pub struct Entry {
+    pub path: PathBuf,
+    pub kind: FileType,
+    pub stat: Stat,
+}
+
+/// How listed paths map to paths within the mount.
+pub struct PathMapping {
+    /// Removed from the start of paths which begin with it.
+    pub strip_prefix: Option<PathBuf>,
+    /// Paths are made relative to this directory; paths outside of it are
+    /// skipped.
+    pub relative_to: Option<PathBuf>,
+    /// Only the file name is kept.
+    pub flatten: bool,
+    /// The rules, which give the name files are shown under.
+    pub rules: Arc<Rules>,
+}
+
+impl PathMapping {
+    /// Returns the path within the mount for a listed path. A path which is
+    /// stripped down to nothing is the mount root.
+    ///
+    /// Directories keep their names; `timeout` and `jobs` apply to the name
+    /// command.
+    pub fn map(
+        &self,
+        path: &Path,
+        kind: FileType,
+        timeout: Option<Duration>,
+        jobs: &Jobs,
+    ) -> Result<PathBuf, String> {
+        let mut mapped = path;
+        if let Some(prefix) = &self.strip_prefix {
+            mapped = mapped.strip_prefix(prefix).unwrap_or(mapped);
+        }
+        if let Some(base) = &self.relative_to {
+            mapped = mapped
+                .strip_prefix(base)
+                .map_err(|_| format!("{:?} is not within {:?}", path, base))?;
+        }
+        if self.flatten {
+            mapped = mapped
+                .file_name()
+                .map(Path::new)
+                .ok_or_else(|| format!("{:?} has no file name", path))?;
+        }
+        match (&self.rules.get(path).naming, mapped.file_name()) {
+            (Some(naming), Some(name)) if kind != FileType::Directory => {
+                Ok(mapped.with_file_name(naming.rename(name, path, timeout, jobs)?))
+            }
+            _ => Ok(mapped.to_owned()),
+        }
+    }
+
+    /// Returns the listed path for a new entry at `path` within the mount,
+    /// undoing `--relative-to` or `--strip-prefix`. Flattening and renaming
+    /// can't be undone, so the name is kept as it is.
+    pub fn source(&self, path: &Path) -> PathBuf {
+        match self.base() {
+            Some(base) => base.join(path),
+            None => path.to_owned(),
+        }
+    }
+
+    /// The directory `--relative-to` or `--strip-prefix` removes from listed
+    /// paths.
+    pub fn base(&self) -> Option<&Path> {
+        self.relative_to.as_deref().or(self.strip_prefix.as_deref())
+    }
+}
+
+/// Parses the output of the list command. Invalid entries are logged and
+/// skipped.
+pub fn parse(format: ListFormat, stdout: &[u8]) -> Vec<Entry> {
+    let separator = match format {
+        ListFormat::Null => b'\0',
+        _ => b'\n',
+    };
+    let mut entries = Vec::new();
+    for (i, item) in stdout.split(|c| *c == separator).enumerate() {
+        let item = match format {
+            ListFormat::Null => item,
+            _ => item.strip_suffix(b"\r").unwrap_or(item),
+        };
+        let blank = match format {
+            ListFormat::Null => item.is_empty(),
+            _ => item.iter().all(u8::is_ascii_whitespace),
+        };
+        if blank {
+            continue;
+        }
+        match parse_entry(format, item) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => warn!("Skipping entry {} of the list: {}", i + 1, e),
+        }
+    }
+    entries
+}
+
+fn parse_entry(format: ListFormat, item: &[u8]) -> Result<Entry, String> {
+    let (mut path, mut kind, stat) = match format {
+        ListFormat::Lines => (
+            item.trim_ascii_end().to_vec(),
+            FileType::RegularFile,
+            Stat::default(),
+        ),
+        ListFormat::Escaped => (unescape(item)?, FileType::RegularFile, Stat::default()),
+        ListFormat::Null => (item.to_vec(), FileType::RegularFile, Stat::default()),
+        ListFormat::Jsonl => {
+            let value: Value = serde_json::from_slice(item).map_err(|e| e.to_string())?;
+            let path = value
+                .get("path")
+                .and_then(Value::as_str)
+                .ok_or("expected an object with a `path` string")?;
+            let stat = stat::from_json(&value, "type")?;
+            let kind = stat.kind.unwrap_or(FileType::RegularFile);
+            (path.as_bytes().to_vec(), kind, stat)
+        }
+    };
+    if format != ListFormat::Jsonl && path.len() > 1 && path.ends_with(b"/") {
+        path.pop();
+        kind = FileType::Directory;
+    }
+    check_path(&path)?;
+    Ok(Entry {
+        path: PathBuf::from(OsString::from_vec(path)),
+        kind,
+        stat,
+    })
+}
+
+/// Rejects paths which can't name a file in the mount.
+fn check_path(path: &[u8]) -> Result<(), String> {
+    let shown = String::from_utf8_lossy(path);
+    if path.contains(&b'\0') {
+        return Err(format!("`{}` contains a NUL byte", shown));
+    }
+    for (i, component) in path.split(|c| *c == b'/').enumerate() {
+        // a leading `/` is fine, but not `a//b`; a trailing `/` marking a
+        // directory was already removed, so `a//` is rejected as well
+        if component.is_empty() && i > 0 {
+            return Err(format!("`{}` has an empty component", shown));
+        }
+        if component == b".." {
+            return Err(format!("`{}` has a `..` component", shown));
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a path in the `escaped` format.
+fn unescape(item: &[u8]) -> Result<Vec<u8>, String> {
+    let item = item.trim_ascii();
+    let item = match item {
+        [b'"', quoted @ .., b'"'] => quoted,
+        _ => item,
+    };
+    let mut path = Vec::with_capacity(item.len());
+    let mut bytes = item.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            path.push(byte);
+            continue;
+        }
+        let escaped = bytes.next().ok_or("ends in an unfinished escape")?;
+        path.push(match escaped {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'v' => 0x0b,
+            b'\\' | b'"' | b'\'' | b' ' => escaped,
+            b'x' => {
+                let mut value = 0u8;
+                for _ in 0..2 {
+                    let digit = bytes
+                        .next()
+                        .and_then(|c| (c as char).to_digit(16))
+                        .ok_or("expected two hex digits after `\\x`")?;
+                    value = value * 16 + digit as u8;
+                }
+                value
+            }
+            b'0'..=b'7' => {
+                let mut value = u32::from(escaped - b'0');
+                for _ in 0..2 {
+                    match bytes.peek() {
+                        Some(c @ b'0'..=b'7') => {
+                            value = value * 8 + u32::from(c - b'0');
+                            bytes.next();
+                        }
+                        _ => break,
+                    }
+                }
+                u8::try_from(value)
+                    .map_err(|_| format!("octal escape `\\{:o}` is out of range", value))?
+            }
+            _ => return Err(format!("unknown escape `\\{}`", escaped as char)),
+        });
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_plain_and_quoted() {
+        assert_eq!(unescape(b"a b.txt").unwrap(), b"a b.txt");
+        assert_eq!(unescape(b"  \"a b.txt\"  ").unwrap(), b"a b.txt");
+        assert_eq!(unescape(b"\"\"").unwrap(), b"");
+    }
+
+    #[test]
+    fn unescape_escapes() {
+        assert_eq!(unescape(br#"a\nb\tc"#).unwrap(), b"a\nb\tc");
+        assert_eq!(unescape(br#"\\\"\'\ a"#).unwrap(), b"\\\"' a");
+        assert_eq!(unescape(br#"\x1b\x7F"#).unwrap(), b"\x1b\x7f");
+        assert_eq!(unescape(br#"\033\0\12"#).unwrap(), b"\x1b\0\n");
+        // at most three octal digits
+        assert_eq!(unescape(br#"\0101"#).unwrap(), b"\x081");
+    }
+
+    #[test]
+    fn unescape_rejects_invalid_escapes() {
+        assert!(unescape(br#"a\"#).is_err());
+        assert!(unescape(br#"\q"#).is_err());
+        assert!(unescape(br#"\x1"#).is_err());
+        assert!(unescape(br#"\xzz"#).is_err());
+        assert!(unescape(br#"\400"#).is_err());
+    }
+
+    #[test]
+    fn trailing_slash_marks_directories() {
+        let entries = parse(ListFormat::Lines, b"a/\nb\nc//\n");
+        let listed: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.path.to_str().unwrap(), entry.kind))
+            .collect();
+        assert_eq!(
+            listed,
+            [("a", FileType::Directory), ("b", FileType::RegularFile),]
+        );
+        let entries = parse(ListFormat::Null, b"a/\0");
+        assert_eq!(entries[0].kind, FileType::Directory);
+    }
+
+    #[test]
+    fn check_path_accepts_valid_paths() {
+        assert!(check_path(b"a").is_ok());
+        assert!(check_path(b"/a/b.txt").is_ok());
+        assert!(check_path(b"a/..b/c..").is_ok());
+    }
+
+    #[test]
+    fn check_path_rejects_invalid_paths() {
+        assert!(check_path(b"a//b").is_err());
+        assert!(check_path(b"a/").is_err());
+        assert!(check_path(b"a/../b").is_err());
+        assert!(check_path(b"..").is_err());
+        assert!(check_path(b"a\0b").is_err());
+    }
+}
@@ -1,223 +1,348 @@
-use clap::Clap;
-use fuse::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, Request,
-};
-use libc::ENOENT;
-use log::info;
-use std::env;
-use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStrExt;
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::time::{Duration, UNIX_EPOCH};
-
-const TTL: Duration = Duration::from_secs(0);
-
-fn attr(ino: u64, kind: FileType) -> FileAttr {
-    FileAttr {
-        ino: ino,
-        size: 1_000_000_000_000,
-        blocks: 1,
-        atime: UNIX_EPOCH,
-        mtime: UNIX_EPOCH,
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind,
-        perm: 0o644,
-        nlink: 1,
-        uid: 0,
-        gid: 0,
-        rdev: 0,
-        flags: 0,
-    }
-}
-
-#[derive(Debug)]
-struct Inode {
-    path: PathBuf,
-    kind: FileType,
-    parent_inode: u64,
-}
-
-fn insert_path(inode_map: &mut Vec<Inode>, path: &Path, kind: FileType) -> u64 {
-    let parent_inode = if let Some(parent) = path.parent() {
-        if let Some((i, _)) = inode_map.iter().enumerate().find(|(_, e)| e.path == parent) {
-            (i + 1) as u64
-        } else {
-            insert_path(inode_map, parent, FileType::Directory)
-        }
-    } else {
-        1
-    };
-
-    if path != Path::new("/") && path != Path::new(".") {
-        inode_map.push(Inode {
-            path: path.to_owned(),
-            kind,
-            parent_inode,
-        });
+mod buffer;
+mod cache;
+mod command;
+mod config;
+mod fs;
+mod handles;
+mod inode;
+mod jobs;
+mod list;
+mod name;
+mod rule;
+mod size;
+mod snapshot;
+mod source;
+mod spool;
+mod stat;
 
-        inode_map.len() as u64
-    } else {
-        1
-    }
-}
+use clap::{AppSettings, Clap};
+use command::ExitMapping;
+use fs::ShellFS;
+use list::ListFormat;
+use name::{NameTemplate, Naming};
+use rule::{Rule, RuleSpec, Rules};
+use size::SizeStrategy;
+use snapshot::RefreshPolicy;
+use std::env;
+use std::ffi::OsStr;
+use std::path::PathBuf;
 
 #[derive(Clap)]
 #[clap(author = clap::crate_authors!(), version = clap::crate_version!())]
-struct ShellFS {
+#[clap(setting = AppSettings::AllArgsOverrideSelf)]
+struct Options {
+    /// TOML file with the options of the mount, such as `list = "ls"`;
+    /// options given on the command line take precedence, and their
+    /// `--rule`s are tried before the config's
+    #[clap(short, long)]
+    config: Option<PathBuf>,
     /// Where to mount the file system
     #[clap(short, long)]
-    mountpoint: String,
+    mountpoint: Option<String>,
+    /// Mount option passed on to FUSE, e.g. `allow_other`; may be repeated
+    #[clap(short = 'o', long = "option", number_of_values = 1)]
+    mount_options: Vec<String>,
     /// Command which lists the files in the file system
     #[clap(short, long)]
-    list: String,
+    list: Option<String>,
+    /// How the list command's output is read: `lines` (one path per line),
+    /// `escaped` (one path per line with C-style escapes, optionally in
+    /// double quotes), `null` (NUL-terminated paths, as from `find -print0`)
+    /// or `jsonl` (one JSON object per line, such as `{"path": "a/b",
+    /// "type": "file", "size": 3}`, with the same metadata fields as --stat
+    /// plus `xattrs`); except in `jsonl`, a path ending in `/` is a
+    /// directory, which is shown even if nothing is listed below it
+    #[clap(long, default_value = "lines")]
+    list_format: ListFormat,
+    /// Prefix removed from listed paths which begin with it, e.g. `/home/me/`
+    #[clap(long)]
+    strip_prefix: Option<PathBuf>,
+    /// Directory which listed paths are made relative to; paths outside of
+    /// it are skipped
+    #[clap(long)]
+    relative_to: Option<PathBuf>,
+    /// Put every listed file directly in the mount root, under its file name
+    #[clap(long)]
+    flatten: bool,
+    /// Turn off --flatten, e.g. where the config sets it
+    #[clap(long)]
+    no_flatten: bool,
+    /// Name files are shown under, made from the listed name with
+    /// `{name}`, `{stem}` and `{ext}`, e.g. `{stem}.txt`
+    #[clap(long)]
+    name: Option<NameTemplate>,
+    /// Command which gets the listed path as $INPUT and prints the name the
+    /// file is shown under; it runs once for each path the list command
+    /// prints, not again when the list is refreshed
+    #[clap(long)]
+    name_command: Option<String>,
     /// Command which generates the content of each file in the file system
     #[clap(short, long)]
-    transform: String,
-}
-
-impl ShellFS {
-    fn transform(&self, item: &Path) -> Vec<u8> {
-        Command::new("sh")
-            .arg("-c")
-            .arg(&*self.transform)
-            .env("INPUT", item.as_os_str())
-            .output()
-            .expect("Failed to execute transform command.")
-            .stdout
-    }
-
-    fn items(&self) -> Vec<Inode> {
-        let stdout = Command::new("sh")
-            .arg("-c")
-            .arg(&*self.list)
-            .output()
-            .expect("Failed to execute list command.")
-            .stdout;
-        // split stdout into lines
-        let stdout = stdout.split(|c| *c == b'\n');
-        let os_strs = stdout.map(|s| OsStr::from_bytes(s));
-        let os_strs = os_strs.filter(|s| !s.is_empty());
-        let mut inode_map = vec![Inode {
-            path: PathBuf::from(""),
-            kind: FileType::Directory,
-            parent_inode: 0,
-        }];
-        for path in os_strs.map(|s| Path::new(s)) {
-            insert_path(&mut inode_map, path, FileType::RegularFile);
-        }
-        inode_map
-    }
+    transform: Option<String>,
+    /// A JSON object such as `{"glob": "*.pdf", "transform": "pdftotext
+    /// \"$INPUT\" -", "name": "{stem}.txt"}`, giving the transform, `name`,
+    /// `name_command` or `size_strategy` for the paths matching its `glob`
+    /// or `regex`; may be repeated, and the first matching rule applies
+    #[clap(long = "rule", number_of_values = 1)]
+    rules: Vec<RuleSpec>,
+    /// When to re-run the list command: `never`, `on-demand` (after SIGHUP)
+    /// or an interval in seconds
+    #[clap(short, long, default_value = "on-demand")]
+    refresh: RefreshPolicy,
+    /// How file sizes are determined: `transform` (run the transform and
+    /// measure its output), `command` (run the --size command, or take the
+    /// size given by --stat or the list, measuring the transform output if
+    /// neither gives one) or `zero` (report 0 and use direct I/O); defaults
+    /// to `command` if --size, --stat or --list-format jsonl is given and
+    /// `transform` otherwise
+    #[clap(long)]
+    size_strategy: Option<SizeStrategy>,
+    /// Command which prints the size in bytes of the transformed file
+    #[clap(short, long)]
+    size: Option<String>,
+    /// Command which gets the new content of a file opened for writing on
+    /// stdin when it is closed, with the file's path as $INPUT; without it
+    /// the file system is read-only
+    #[clap(long)]
+    write: Option<String>,
+    /// Command which creates the file $INPUT, for files created in the mount
+    #[clap(long)]
+    create: Option<String>,
+    /// Command which deletes the file $INPUT, for files deleted in the mount
+    #[clap(long)]
+    unlink: Option<String>,
+    /// Command which moves the file $FROM to $TO, replacing anything at $TO,
+    /// for files renamed or moved in the mount
+    #[clap(long)]
+    rename: Option<String>,
+    /// Command which creates the directory $INPUT, for directories created in
+    /// the mount
+    #[clap(long)]
+    mkdir: Option<String>,
+    /// Command which removes the directory $INPUT, for directories removed
+    /// in the mount
+    #[clap(long)]
+    rmdir: Option<String>,
+    /// Command which prints the metadata of a file as a JSON object with
+    /// any of `size`, `mtime`, `mode`, `uid`, `gid`, `kind` (`file`, `dir`
+    /// or `symlink`) and `target`
+    #[clap(long)]
+    stat: Option<String>,
+    /// Directory in which transform outputs are kept between mounts
+    #[clap(long)]
+    cache_dir: Option<PathBuf>,
+    /// Maximum total size of the cache directory, e.g. `512M` or `2G`
+    #[clap(long, default_value = "1G", parse(try_from_str = cache::parse_bytes))]
+    cache_size: u64,
+    /// Command which prints a fingerprint of the source of a file; cached
+    /// outputs are only reused while it prints the same fingerprint
+    #[clap(long)]
+    fingerprint: Option<String>,
+    /// Treat listed paths as source files, so that cached outputs are
+    /// dropped when the file's mtime, size or inode changes
+    #[clap(long)]
+    source_files: bool,
+    /// Turn off --source-files, e.g. where the config sets it
+    #[clap(long)]
+    no_source_files: bool,
+    /// Watch source files with inotify instead of checking them on every
+    /// access (requires --source-files)
+    #[clap(long)]
+    watch: bool,
+    /// Turn off --watch, e.g. where the config sets it
+    #[clap(long)]
+    no_watch: bool,
+    /// Serve the output of commands even if they exit with a non-zero
+    /// status, instead of failing with EIO
+    #[clap(long)]
+    ignore_exit_status: bool,
+    /// Turn off --ignore-exit-status, e.g. where the config sets it
+    #[clap(long)]
+    no_ignore_exit_status: bool,
+    /// Report a command exit code as a specific error, e.g. `2=ENOENT` or
+    /// `13=EACCES`; may be repeated
+    #[clap(long = "errno", number_of_values = 1)]
+    errnos: Vec<ExitMapping>,
+    /// Seconds after which the transform, size, stat, fingerprint, write,
+    /// create, unlink, rename, mkdir and rmdir commands are killed and the
+    /// access fails with ETIMEDOUT
+    #[clap(long)]
+    transform_timeout: Option<u64>,
+    /// Seconds after which the list command is killed and the previous list
+    /// is kept; also applies to the name command
+    #[clap(long)]
+    list_timeout: Option<u64>,
+    /// How many commands may run at the same time; defaults to the number
+    /// of CPUs. Requests are handled by four worker threads per job
+    #[clap(short, long)]
+    jobs: Option<usize>,
+    /// Size beyond which a transform output is moved from memory to a
+    /// temporary file
+    #[clap(long, default_value = "64M", parse(try_from_str = cache::parse_bytes))]
+    spill_threshold: u64,
+    /// Total size of the transform outputs kept in memory, beyond which
+    /// further outputs go to temporary files; also the largest size a file
+    /// can be written with (EFBIG beyond it)
+    #[clap(long, default_value = "512M", parse(try_from_str = cache::parse_bytes))]
+    memory_limit: u64,
+    /// Directory for temporary files; defaults to $TMPDIR or /tmp
+    #[clap(long)]
+    spill_dir: Option<PathBuf>,
+    /// Run the transform for each read, with the byte range to produce in
+    /// $OFFSET and $LENGTH (requires --size-strategy command or zero)
+    #[clap(long)]
+    range_transform: bool,
+    /// Turn off --range-transform, e.g. where the config sets it
+    #[clap(long)]
+    no_range_transform: bool,
 }
 
-impl Filesystem for ShellFS {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        info!("Calling lookup: {} {:?}", parent, name);
-        for (
-            idx,
-            Inode {
-                path,
-                kind,
-                parent_inode,
-            },
-        ) in self.items().into_iter().enumerate()
-        {
-            if parent == parent_inode
-                && name == path.file_name().expect("child path has no file name")
+impl Options {
+    fn size_strategy(&self) -> SizeStrategy {
+        match self.size_strategy {
+            Some(strategy) => strategy,
+            None if self.size.is_some()
+                || self.stat.is_some()
+                || self.list_format == ListFormat::Jsonl =>
             {
-                reply.entry(&TTL, &attr((idx + 1) as u64, kind), 0);
-                return;
+                SizeStrategy::Command
             }
+            None => SizeStrategy::Transform,
         }
-        reply.error(ENOENT);
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
-        info!("Calling getattr: {}", ino);
-        let items = self.items();
-        if ino <= (items.len() as u64) {
-            let item = &self.items()[ino as usize - 1];
-            reply.attr(&TTL, &attr(ino, item.kind));
-        } else {
-            reply.error(ENOENT);
-        }
+    /// Returns the `--rule`s followed by the rule made from the global
+    /// options.
+    fn rules(&self) -> Rules {
+        let naming = match (&self.name, &self.name_command) {
+            (Some(template), _) => Some(Naming::Template(template.clone())),
+            (None, Some(command)) => Some(Naming::Command(command.clone())),
+            (None, None) => None,
+        };
+        let transform = self.transform.clone().unwrap_or_default();
+        let fallback = Rule::fallback(transform, naming, self.size_strategy());
+        Rules::new(self.rules.clone(), fallback)
     }
 
-    fn read(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        reply: ReplyData,
-    ) {
-        info!("Calling read: {} {} {} {}", ino, fh, offset, size);
-        let items = self.items();
-        if ino > items.len() as u64 {
-            reply.error(ENOENT);
-        } else {
-            let item = &items[ino as usize - 1];
-            let data = self.transform(&*item.path);
-            let from = (data.len() as i64 - 1).min(offset).max(0) as usize;
-            let to = (data.len() as i64).min(offset + size as i64).max(0) as usize;
-            reply.data(&data[from..to]);
-        }
+    /// Applies the `--no-*` switches, which turn off switches the config
+    /// turned on.
+    fn switch_off(&mut self) {
+        self.flatten &= !self.no_flatten;
+        self.source_files &= !self.no_source_files;
+        self.watch &= !self.no_watch;
+        self.ignore_exit_status &= !self.no_ignore_exit_status;
+        self.range_transform &= !self.no_range_transform;
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request,
-        ino: u64,
-        fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        info!("Calling readdir: {} {} {}", ino, fh, offset);
-
-        let items = self.items();
+    /// Whether any command changing files was given.
+    fn writable(&self) -> bool {
+        self.write.is_some()
+            || self.create.is_some()
+            || self.unlink.is_some()
+            || self.rename.is_some()
+            || self.mkdir.is_some()
+            || self.rmdir.is_some()
+    }
 
-        if ino > items.len() as u64 {
-            reply.error(ENOENT);
-            return;
+    /// Returns everything wrong with the combination of options.
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (option, name) in &[
+            (&self.mountpoint, "mountpoint"),
+            (&self.list, "list"),
+            (&self.transform, "transform"),
+        ] {
+            if option.is_none() {
+                problems.push(format!(
+                    "--{} (or `{}` in the config) is required.",
+                    name, name
+                ));
+            }
         }
-
-        let mut entries = vec![
-            (ino, FileType::Directory, OsString::from(".")),
-            (ino, FileType::Directory, OsString::from("..")),
-        ];
-
-        for (idx, inode) in items
-            .into_iter()
-            .enumerate()
-            .filter(|(_, i)| i.parent_inode == ino)
+        let strategies: Vec<SizeStrategy> =
+            self.rules().iter().map(|rule| rule.size_strategy).collect();
+        if strategies.contains(&SizeStrategy::Command)
+            && self.size.is_none()
+            && self.stat.is_none()
+            && self.list_format != ListFormat::Jsonl
         {
-            if let Some(name) = inode.path.file_name() {
-                entries.push(((idx + 1) as u64, inode.kind, name.to_owned()));
-            }
+            problems.push(
+                "--size-strategy command requires --size, --stat or --list-format jsonl."
+                    .to_owned(),
+            );
         }
-
-        for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            // i + 1 means the index of the next entry
-            reply.add(entry.0, (i + 1) as i64, entry.1, entry.2);
+        if self.range_transform && strategies.contains(&SizeStrategy::Transform) {
+            problems.push(
+                "--range-transform requires --size-strategy command or zero for every rule."
+                    .to_owned(),
+            );
         }
-        reply.ok();
+        if self.strip_prefix.is_some() && self.relative_to.is_some() {
+            problems.push("--strip-prefix and --relative-to can't be used together.".to_owned());
+        }
+        if self.name.is_some() && self.name_command.is_some() {
+            problems.push("--name and --name-command can't be used together.".to_owned());
+        }
+        if self.watch && !self.source_files {
+            problems.push("--watch requires --source-files.".to_owned());
+        }
+        problems
     }
 }
 
 fn main() {
     env_logger::init();
-    let shellfs = ShellFS::parse();
-    let options = ["-o", "ro", "-o", "fsname=hello"]
+    let mut options = Options::parse();
+    if let Some(path) = options.config.clone() {
+        let flags = config::load(&path).unwrap_or_else(|problems| {
+            eprintln!("Invalid config {:?}:", path);
+            for problem in problems {
+                eprintln!("  {}", problem);
+            }
+            std::process::exit(2);
+        });
+        // the command line comes last, so that its options override the
+        // config's
+        let mut args = env::args_os();
+        let cli_rules = options.rules.len();
+        options = Options::parse_from(args.next().into_iter().chain(flags).chain(args));
+        // but the first matching rule applies, so its rules go first
+        options.rules.rotate_right(cli_rules);
+    }
+    options.switch_off();
+    let problems = options.problems();
+    if !problems.is_empty() {
+        for problem in problems {
+            eprintln!("{}", problem);
+        }
+        std::process::exit(2);
+    }
+    let mut mount_options: Vec<String> = ["-o", "fsname=hello"]
+        .iter()
+        .map(|o| o.to_string())
+        .collect();
+    if !options.writable() {
+        mount_options.push("-o".to_owned());
+        mount_options.push("ro".to_owned());
+    }
+    for option in &options.mount_options {
+        mount_options.push("-o".to_owned());
+        mount_options.push(option.clone());
+    }
+    let mount_options = mount_options
         .iter()
         .map(|o| o.as_ref())
         .collect::<Vec<&OsStr>>();
-    let mountpoint = shellfs.mountpoint.clone();
+    let mountpoint = options
+        .mountpoint
+        .clone()
+        .expect("--mountpoint is required.");
+    let shellfs = ShellFS::new(options);
 
     daemonize_me::Daemon::new()
         .work_dir(".")
         .start()
         .expect("Couldn't daemonize.");
-    fuse::mount(shellfs, mountpoint, &options).unwrap();
+    snapshot::install_sighup_handler();
+    fuse::mount(shellfs, mountpoint, &mount_options).unwrap();
 }
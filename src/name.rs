@@ -0,0 +1,135 @@
+use crate::command;
+use crate::jobs::Jobs;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    /// `{name}`, the whole file name.
+    Name,
+    /// `{stem}`, the file name without its extension.
+    Stem,
+    /// `{ext}`, the extension without the dot.
+    Ext,
+}
+
+/// A template for the name a file is shown under, such as `{stem}.txt`.
+/// `{{` and `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed `{{` in `{}`", s))?;
+                    let part = match &rest[..end] {
+                        "name" => Part::Name,
+                        "stem" => Part::Stem,
+                        "ext" => Part::Ext,
+                        other => {
+                            return Err(format!(
+                                "expected `{{name}}`, `{{stem}}` or `{{ext}}`, got `{{{}}}`",
+                                other
+                            ))
+                        }
+                    };
+                    chars = rest[end + 1..].chars();
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err(format!("unmatched `}}` in `{}`", s)),
+                '/' => return Err(format!("`{}` would put a `/` in file names", s)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(NameTemplate { parts })
+    }
+}
+
+impl NameTemplate {
+    pub fn render(&self, name: &OsStr) -> OsString {
+        let path = Path::new(name);
+        let mut rendered = Vec::new();
+        for part in &self.parts {
+            let bytes = match part {
+                Part::Literal(literal) => literal.as_bytes(),
+                Part::Name => name.as_bytes(),
+                Part::Stem => path.file_stem().unwrap_or(name).as_bytes(),
+                Part::Ext => path.extension().map_or(&[][..], OsStrExt::as_bytes),
+            };
+            rendered.extend_from_slice(bytes);
+        }
+        OsString::from_vec(rendered)
+    }
+}
+
+/// How the name a file is shown under is derived from its listed path.
+#[derive(Debug, Clone)]
+pub enum Naming {
+    Template(NameTemplate),
+    /// A command which gets the listed path as `$INPUT` and prints the name.
+    Command(String),
+}
+
+impl Naming {
+    /// Returns the name to show the file listed as `source` under, in place
+    /// of `name`. The name command counts as one of the `jobs`.
+    pub fn rename(
+        &self,
+        name: &OsStr,
+        source: &Path,
+        timeout: Option<Duration>,
+        jobs: &Jobs,
+    ) -> Result<OsString, String> {
+        let renamed = match self {
+            Naming::Template(template) => template.render(name),
+            Naming::Command(command) => {
+                let _job = jobs.acquire();
+                let env = [("INPUT", source.as_os_str())];
+                let mut stdout = command::run(command, &env, timeout)
+                    .map_err(|e| format!("name command failed: {}", e))?;
+                while let Some(b'\n') | Some(b'\r') = stdout.last() {
+                    stdout.pop();
+                }
+                OsString::from_vec(stdout)
+            }
+        };
+        let bytes = renamed.as_bytes();
+        if bytes.is_empty() || bytes == b"." || bytes == b".." || bytes.contains(&b'/') {
+            return Err(format!("{:?} is not a valid file name", renamed));
+        }
+        if bytes.contains(&b'\0') {
+            return Err(format!("{:?} contains a NUL byte", renamed));
+        }
+        Ok(renamed)
+    }
+}
@@ -0,0 +1,226 @@
+use crate::name::{NameTemplate, Naming};
+use crate::size::SizeStrategy;
+use regex::bytes::Regex;
+use serde_json::Value;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Which listed paths a rule applies to.
+#[derive(Debug, Clone)]
+struct Pattern {
+    regex: Regex,
+    /// Set for globs without a `/`, which are matched against the file name
+    /// only.
+    name_only: bool,
+}
+
+impl Pattern {
+    fn glob(glob: &str) -> Result<Self, String> {
+        let regex = glob_to_regex(glob)?;
+        Ok(Pattern {
+            regex: Regex::new(&regex).map_err(|e| format!("invalid glob `{}`: {}", glob, e))?,
+            name_only: !glob.contains('/'),
+        })
+    }
+
+    fn regex(regex: &str) -> Result<Self, String> {
+        Ok(Pattern {
+            regex: Regex::new(regex).map_err(|e| format!("invalid regex `{}`: {}", regex, e))?,
+            name_only: false,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        let subject = match path.file_name() {
+            Some(name) if self.name_only => name,
+            _ => path.as_os_str(),
+        };
+        self.regex.is_match(subject.as_bytes())
+    }
+}
+
+/// Translates a glob into an anchored regex, where `*` and `?` don't match
+/// `/` but `**` does.
+fn glob_to_regex(glob: &str) -> Result<String, String> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                regex.push('[');
+                if let Some('!') = chars.peek() {
+                    chars.next();
+                    regex.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => {
+                            if c == '\\' || c == '[' {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        None => return Err(format!("unclosed `[` in glob `{}`", glob)),
+                    }
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+/// A `--rule`, a JSON object such as `{"glob": "*.pdf", "transform":
+/// "pdftotext \"$INPUT\" -", "name": "{stem}.txt"}`.
+///
+/// The paths it applies to are given by `glob` or `regex`; `transform`,
+/// `name`, `name_command` and `size_strategy` default to the global options.
+#[derive(Debug, Clone)]
+pub struct RuleSpec {
+    pattern: Pattern,
+    transform: Option<String>,
+    naming: Option<Naming>,
+    size_strategy: Option<SizeStrategy>,
+}
+
+impl FromStr for RuleSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value: Value = serde_json::from_str(s).map_err(|e| e.to_string())?;
+        RuleSpec::from_json(&value)
+    }
+}
+
+impl RuleSpec {
+    pub fn from_json(value: &Value) -> Result<Self, String> {
+        if value.as_object().is_none() {
+            return Err("expected a rule to be an object".to_owned());
+        }
+        let string = |name| match value.get(name) {
+            Some(value) => value
+                .as_str()
+                .map(Some)
+                .ok_or_else(|| format!("expected `{}` to be a string", name)),
+            None => Ok(None),
+        };
+        let pattern = match (string("glob")?, string("regex")?) {
+            (Some(glob), None) => Pattern::glob(glob)?,
+            (None, Some(regex)) => Pattern::regex(regex)?,
+            _ => return Err("expected a rule to have either `glob` or `regex`".to_owned()),
+        };
+        let naming = match (string("name")?, string("name_command")?) {
+            (Some(_), Some(_)) => {
+                return Err(
+                    "expected a rule to have at most one of `name` and `name_command`".to_owned(),
+                )
+            }
+            (Some(template), None) => Some(Naming::Template(template.parse::<NameTemplate>()?)),
+            (None, Some(command)) => Some(Naming::Command(command.to_owned())),
+            (None, None) => None,
+        };
+        Ok(RuleSpec {
+            pattern,
+            transform: string("transform")?.map(str::to_owned),
+            naming,
+            size_strategy: string("size_strategy")?.map(str::parse).transpose()?,
+        })
+    }
+}
+
+/// How the files a rule applies to are served.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Option<Pattern>,
+    pub transform: String,
+    pub naming: Option<Naming>,
+    pub size_strategy: SizeStrategy,
+}
+
+impl Rule {
+    /// The rule applying to paths no `--rule` matches, made from the global
+    /// options.
+    pub fn fallback(
+        transform: String,
+        naming: Option<Naming>,
+        size_strategy: SizeStrategy,
+    ) -> Self {
+        Rule {
+            pattern: None,
+            transform,
+            naming,
+            size_strategy,
+        }
+    }
+}
+
+/// The rules in order, followed by the fallback rule.
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(specs: Vec<RuleSpec>, fallback: Rule) -> Self {
+        let mut rules: Vec<Rule> = specs
+            .into_iter()
+            .map(|spec| Rule {
+                pattern: Some(spec.pattern),
+                transform: spec.transform.unwrap_or_else(|| fallback.transform.clone()),
+                naming: spec.naming.or_else(|| fallback.naming.clone()),
+                size_strategy: spec.size_strategy.unwrap_or(fallback.size_strategy),
+            })
+            .collect();
+        rules.push(fallback);
+        Rules { rules }
+    }
+
+    /// Returns the first rule matching the listed path `source`.
+    pub fn get(&self, source: &Path) -> &Rule {
+        self.rules
+            .iter()
+            .find(|rule| match &rule.pattern {
+                Some(pattern) => pattern.matches(source),
+                None => true,
+            })
+            .expect("the fallback rule matches every path")
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Rule> {
+        self.rules.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_stars() {
+        assert_eq!(glob_to_regex("*.pdf").unwrap(), r"^[^/]*\.pdf$");
+        assert_eq!(glob_to_regex("docs/**").unwrap(), "^docs/.*$");
+        assert_eq!(glob_to_regex("a?c").unwrap(), "^a[^/]c$");
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert_eq!(glob_to_regex("[a-z].txt").unwrap(), r"^[a-z]\.txt$");
+        assert_eq!(glob_to_regex("[!0-9]").unwrap(), "^[^0-9]$");
+        assert_eq!(glob_to_regex(r"[\[]").unwrap(), r"^[\\\[]$");
+    }
+
+    #[test]
+    fn glob_rejects_unclosed_class() {
+        assert!(glob_to_regex("[a-z").is_err());
+        assert!(glob_to_regex("[!").is_err());
+    }
+}
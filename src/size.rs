@@ -0,0 +1,44 @@
+use std::str::FromStr;
+
+/// How the size reported for a file is determined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeStrategy {
+    /// Run the transform and measure its output; the size is then cached.
+    Transform,
+    /// Run the `--size` command, which prints the size in bytes.
+    Command,
+    /// Report 0 and serve reads with direct I/O, so the kernel reads until
+    /// the transform output ends.
+    Zero,
+}
+
+impl FromStr for SizeStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "transform" => Ok(SizeStrategy::Transform),
+            "command" => Ok(SizeStrategy::Command),
+            "zero" => Ok(SizeStrategy::Zero),
+            _ => Err(format!(
+                "expected `transform`, `command` or `zero`, got `{}`",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses the output of a size command.
+pub fn parse_size(stdout: &[u8]) -> Option<u64> {
+    std::str::from_utf8(stdout).ok()?.trim().parse().ok()
+}
+
+/// Pads with zeros or truncates the bytes read at `offset`, so that reads
+/// match the `promised` size of the file even if the transform output is
+/// shorter or longer.
+pub fn fit(data: &mut Vec<u8>, offset: i64, size: u32, promised: u64) {
+    let expected = promised
+        .saturating_sub(offset.max(0) as u64)
+        .min(u64::from(size));
+    data.resize(expected as usize, 0);
+}
@@ -0,0 +1,300 @@
+use crate::command;
+use crate::inode::{Inode, InodeNumbers, InodeTable};
+use crate::jobs::Jobs;
+use crate::list::{self, ListFormat, PathMapping};
+use crate::stat::Stat;
+use fuse::FileType;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Set from the SIGHUP handler to ask for the list command to be re-run.
+static REFRESH_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How long changes made through the mount have to stop for before the list
+/// command is re-run, so that copying a tree into the mount doesn't run it
+/// for every file.
+const SETTLE: Duration = Duration::from_secs(1);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    REFRESH_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes SIGHUP mark the current snapshot as stale.
+pub fn install_sighup_handler() {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+/// When the list command is re-run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RefreshPolicy {
+    /// Run the list command once, at the first access.
+    Never,
+    /// Re-run the list command after the daemon receives SIGHUP.
+    OnDemand,
+    /// Re-run the list command once the snapshot is older than the interval
+    /// (or after SIGHUP).
+    Interval(Duration),
+}
+
+impl FromStr for RefreshPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(RefreshPolicy::Never),
+            "on-demand" => Ok(RefreshPolicy::OnDemand),
+            secs => secs
+                .trim_end_matches('s')
+                .parse()
+                .map(|secs| RefreshPolicy::Interval(Duration::from_secs(secs)))
+                .map_err(|_| {
+                    format!(
+                        "expected `never`, `on-demand` or an interval in seconds, got `{}`",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+/// What running the list command takes, shared with the thread re-running
+/// it while the previous snapshot stays in use.
+struct Lister {
+    list: String,
+    format: ListFormat,
+    mapping: PathMapping,
+    timeout: Option<Duration>,
+    numbers: Mutex<InodeNumbers>,
+    /// The kind and path within the mount of each listed path, so that the
+    /// name command only runs for paths which weren't listed before.
+    names: Mutex<HashMap<PathBuf, (FileType, PathBuf)>>,
+}
+
+/// A run of the list command which is due, to be done without holding on
+/// to the snapshot.
+pub struct Refresh {
+    lister: Arc<Lister>,
+    started: Instant,
+    new_generation: bool,
+}
+
+impl Refresh {
+    /// Runs the list command and builds an inode table from its output, or
+    /// returns `None` if it couldn't be run.
+    pub fn run(&self, jobs: &Jobs) -> Option<InodeTable> {
+        let lister = &self.lister;
+        let stdout = match command::run(&lister.list, &[], lister.timeout) {
+            Ok(stdout) => stdout,
+            // e.g. `find` exits with 1 if any directory is unreadable, but
+            // still lists everything else
+            Err(command::Error::Status {
+                code,
+                stdout,
+                stderr,
+            }) => {
+                warn!(
+                    "List command exited with status {:?}, using its output anyway: {}",
+                    code,
+                    String::from_utf8_lossy(&stderr).trim_end()
+                );
+                stdout
+            }
+            Err(e) => {
+                warn!("List command failed, keeping the previous list: {}", e);
+                return None;
+            }
+        };
+        let mut inodes = InodeTable::new(lister.mapping.base().map(Path::to_owned));
+        // only paths which are still listed are kept
+        let known = mem::take(&mut *lister.names.lock().unwrap());
+        let mut names = HashMap::new();
+        for entry in list::parse(lister.format, &stdout) {
+            let source = entry.path;
+            let (kind, stat) = (entry.kind, entry.stat);
+            let mapped = match known.get(&source) {
+                Some((known_kind, path)) if *known_kind == kind => Ok(path.clone()),
+                _ => lister.mapping.map(&source, kind, lister.timeout, jobs),
+            };
+            let inserted = mapped.and_then(|path| {
+                let mut numbers = lister.numbers.lock().unwrap();
+                inodes.insert_entry(&mut numbers, &path, &source, kind, stat)?;
+                names.insert(source.clone(), (kind, path));
+                Ok(())
+            });
+            if let Err(e) = inserted {
+                warn!("Skipping {:?} from the list: {}", source, e);
+            }
+        }
+        *lister.names.lock().unwrap() = names;
+        info!("Refreshed list snapshot: {} inodes", inodes.len());
+        Some(inodes)
+    }
+}
+
+/// The inode table built from the last run of the list command.
+pub struct Snapshot {
+    lister: Arc<Lister>,
+    policy: RefreshPolicy,
+    inodes: InodeTable,
+    taken: Option<Instant>,
+    /// When a change was last made through the mount, unless the list
+    /// command has been re-run since.
+    last_change: Option<Instant>,
+    generation: u64,
+    /// Set while the list command runs, so that it only runs once at a time.
+    refreshing: bool,
+}
+
+impl Snapshot {
+    pub fn new(
+        list: String,
+        format: ListFormat,
+        mapping: PathMapping,
+        policy: RefreshPolicy,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let inodes = InodeTable::new(mapping.base().map(Path::to_owned));
+        Snapshot {
+            lister: Arc::new(Lister {
+                list,
+                format,
+                mapping,
+                timeout,
+                numbers: Mutex::new(InodeNumbers::default()),
+                names: Mutex::new(HashMap::new()),
+            }),
+            policy,
+            inodes,
+            taken: None,
+            last_change: None,
+            generation: 0,
+            refreshing: false,
+        }
+    }
+
+    fn is_stale(&self) -> bool {
+        let taken = match self.taken {
+            Some(taken) => taken,
+            None => return true,
+        };
+        match self.policy {
+            RefreshPolicy::Never => false,
+            RefreshPolicy::OnDemand => REFRESH_REQUESTED.load(Ordering::SeqCst),
+            RefreshPolicy::Interval(interval) => {
+                REFRESH_REQUESTED.load(Ordering::SeqCst) || taken.elapsed() >= interval
+            }
+        }
+    }
+
+    /// Returns the inode table of the last run of the list command, with the
+    /// changes made through the mount since.
+    pub fn inodes(&self) -> &InodeTable {
+        &self.inodes
+    }
+
+    /// Whether the list command has run successfully yet.
+    pub fn is_listed(&self) -> bool {
+        self.taken.is_some()
+    }
+
+    /// Returns the run of the list command which is due, if the refresh
+    /// policy says the snapshot is stale or changes made through the mount
+    /// have settled, and it isn't already running.
+    pub fn start_refresh(&mut self) -> Option<Refresh> {
+        if self.refreshing {
+            return None;
+        }
+        let new_generation = if self.is_stale() {
+            true
+        } else if matches!(self.last_change, Some(changed) if changed.elapsed() >= SETTLE) {
+            // the changes were already applied to the inode table, and
+            // anything cached for the paths they touched was dropped
+            false
+        } else {
+            return None;
+        };
+        REFRESH_REQUESTED.store(false, Ordering::SeqCst);
+        self.refreshing = true;
+        Some(Refresh {
+            lister: self.lister.clone(),
+            started: Instant::now(),
+            new_generation,
+        })
+    }
+
+    /// Puts the inode table built by `refresh` in place. Unless the refresh
+    /// started a new generation, what was derived from the previous snapshot
+    /// is kept.
+    pub fn finish_refresh(&mut self, refresh: Refresh, inodes: Option<InodeTable>) {
+        self.refreshing = false;
+        let changed = matches!(self.last_change, Some(changed) if changed >= refresh.started);
+        if !changed {
+            self.last_change = None;
+        }
+        match inodes {
+            // it may be missing what was changed while the command ran, so
+            // wait for the changes to settle and list again
+            Some(_) if changed => {
+                if refresh.new_generation {
+                    REFRESH_REQUESTED.store(true, Ordering::SeqCst);
+                }
+            }
+            Some(inodes) => {
+                self.inodes = inodes;
+                self.taken = Some(Instant::now());
+                if refresh.new_generation {
+                    self.generation += 1;
+                }
+            }
+            // without a previous list, retry at the next access
+            None if self.taken.is_some() => self.taken = Some(Instant::now()),
+            None => {}
+        }
+    }
+
+    /// Counts the refreshes so far, so that callers can tell when anything
+    /// derived from the previous snapshot has to be thrown away.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Returns the listed path for a new entry at `path` within the mount.
+    pub fn source(&self, path: &Path) -> PathBuf {
+        self.lister.mapping.source(path)
+    }
+
+    /// Adds an entry created through the mount, ahead of the list command
+    /// listing it.
+    pub fn insert(&mut self, path: &Path, source: &Path, kind: FileType) -> Result<u64, String> {
+        let mut numbers = self.lister.numbers.lock().unwrap();
+        self.inodes
+            .insert_entry(&mut numbers, path, source, kind, Stat::default())
+    }
+
+    /// Drops an entry removed through the mount, ahead of the list command
+    /// leaving it out.
+    pub fn remove(&mut self, ino: u64) -> Option<Inode> {
+        self.inodes.remove(ino)
+    }
+
+    /// Moves an entry renamed through the mount, ahead of the list command
+    /// listing it at its new path.
+    pub fn rename(&mut self, ino: u64, parent: u64, path: &Path, source: &Path) {
+        let mut numbers = self.lister.numbers.lock().unwrap();
+        self.inodes.rename(&mut numbers, ino, parent, path, source)
+    }
+
+    /// Notes a change made through the mount, so that the list command is
+    /// re-run to pick up what the commands did.
+    pub fn note_change(&mut self) {
+        self.last_change = Some(Instant::now());
+    }
+}
@@ -0,0 +1,115 @@
+use log::{info, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+/// Fingerprints a listed path as a source file by its device, inode, size
+/// and mtime, which together change whenever the file is rewritten.
+pub fn fingerprint(path: &Path) -> Vec<u8> {
+    match path.metadata() {
+        Ok(m) => format!(
+            "{}:{}:{}:{}.{}",
+            m.dev(),
+            m.ino(),
+            m.size(),
+            m.mtime(),
+            m.mtime_nsec()
+        )
+        .into_bytes(),
+        Err(e) => {
+            warn!("Failed to stat source file {:?}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+const WATCH_MASK: u32 = libc::IN_MODIFY
+    | libc::IN_ATTRIB
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_MOVE_SELF
+    | libc::IN_DELETE_SELF;
+
+/// Watches source files with inotify.
+///
+/// Events are read without blocking whenever `changed` is called, so the
+/// watcher needs no thread of its own.
+pub struct Watcher {
+    fd: RawFd,
+    watches: HashMap<i32, PathBuf>,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Watcher {
+            fd,
+            watches: HashMap::new(),
+        })
+    }
+
+    pub fn watch(&mut self, path: &Path) {
+        let c_path = match CString::new(path.as_os_str().as_bytes()) {
+            Ok(c_path) => c_path,
+            Err(_) => return,
+        };
+        let wd = unsafe { libc::inotify_add_watch(self.fd, c_path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            warn!(
+                "Failed to watch source file {:?}: {}",
+                path,
+                io::Error::last_os_error()
+            );
+            return;
+        }
+        self.watches.insert(wd, path.to_owned());
+    }
+
+    /// Returns the paths of the source files which changed since the last
+    /// call.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        let mut changed = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let len =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len <= 0 {
+                break;
+            }
+            let mut pos = 0;
+            while pos + mem::size_of::<libc::inotify_event>() <= len as usize {
+                let event = unsafe {
+                    std::ptr::read_unaligned(buf[pos..].as_ptr() as *const libc::inotify_event)
+                };
+                pos += mem::size_of::<libc::inotify_event>() + event.len as usize;
+                let path = if event.mask & libc::IN_IGNORED != 0 {
+                    // the watch is gone, it is re-added when the path is
+                    // fingerprinted again
+                    self.watches.remove(&event.wd)
+                } else {
+                    self.watches.get(&event.wd).cloned()
+                };
+                if let Some(path) = path {
+                    info!("Source file changed: {:?}", path);
+                    changed.push(path);
+                }
+            }
+        }
+        changed
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
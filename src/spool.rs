@@ -0,0 +1,99 @@
+use crate::buffer::Buffer;
+use crate::command::Errno;
+use crate::size;
+use log::warn;
+use std::sync::{Arc, Condvar, Mutex};
+
+struct Progress {
+    buffer: Arc<Buffer>,
+    /// Set once the command has exited.
+    end: Option<Result<(), Errno>>,
+}
+
+/// The output of a transform which is still running, so that reads can be
+/// served as soon as the bytes they cover have been produced.
+///
+/// If the size of the file was promised up front, reads are fitted to it
+/// with `size::fit`.
+pub struct Spool {
+    progress: Mutex<Progress>,
+    grown: Condvar,
+    promised: Option<u64>,
+}
+
+impl Spool {
+    pub fn new(buffer: Buffer, promised: Option<u64>) -> Self {
+        Spool {
+            progress: Mutex::new(Progress {
+                buffer: Arc::new(buffer),
+                end: None,
+            }),
+            grown: Condvar::new(),
+            promised,
+        }
+    }
+
+    pub fn promised(&self) -> Option<u64> {
+        self.promised
+    }
+
+    pub fn push(&self, chunk: &[u8]) {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.end.is_some() {
+            return;
+        }
+        // the buffer is only shared by `wait` once the spool has ended
+        let buffer = Arc::get_mut(&mut progress.buffer).expect("spool buffer shared while filling");
+        if let Err(e) = buffer.push(chunk) {
+            warn!("Failed to spool transform output: {}", e);
+            progress.end = Some(Err(libc::EIO));
+        }
+        self.grown.notify_all();
+    }
+
+    /// Marks the output as complete, unless it already failed.
+    pub fn finish(&self, result: Result<(), Errno>) {
+        self.progress.lock().unwrap().end.get_or_insert(result);
+        self.grown.notify_all();
+    }
+
+    /// Waits until the bytes covered by a read of `size` bytes at `offset`
+    /// have been produced, or the command has exited, and returns them.
+    ///
+    /// Reads past what a failed command produced fail with its errno.
+    pub fn read(&self, offset: i64, size: u32) -> Result<Vec<u8>, Errno> {
+        let mut wanted = (offset.max(0) as u64).saturating_add(u64::from(size));
+        if let Some(promised) = self.promised {
+            wanted = wanted.min(promised);
+        }
+        let mut progress = self.progress.lock().unwrap();
+        while progress.buffer.len() < wanted && progress.end.is_none() {
+            progress = self.grown.wait(progress).unwrap();
+        }
+        match progress.end {
+            Some(Err(errno)) if progress.buffer.len() < wanted => Err(errno),
+            _ => {
+                let mut data = progress.buffer.read(offset, size).map_err(|e| {
+                    warn!("Failed to read spooled transform output: {}", e);
+                    libc::EIO
+                })?;
+                if let Some(promised) = self.promised {
+                    size::fit(&mut data, offset, size, promised);
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    /// Waits for the command to exit and returns its complete output.
+    pub fn wait(&self) -> Result<Arc<Buffer>, Errno> {
+        let mut progress = self.progress.lock().unwrap();
+        loop {
+            match progress.end {
+                Some(Ok(())) => return Ok(progress.buffer.clone()),
+                Some(Err(errno)) => return Err(errno),
+                None => progress = self.grown.wait(progress).unwrap(),
+            }
+        }
+    }
+}
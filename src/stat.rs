@@ -0,0 +1,127 @@
+use fuse::FileType;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Metadata reported for a file, used in place of the defaults for the
+/// fields that are set.
+#[derive(Debug, Clone, Default)]
+pub struct Stat {
+    pub size: Option<u64>,
+    pub mtime: Option<SystemTime>,
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub kind: Option<FileType>,
+    pub target: Option<PathBuf>,
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+impl Stat {
+    /// Fills in the fields which aren't set from `fallback`.
+    pub fn or(self, fallback: Stat) -> Stat {
+        let mut xattrs = fallback.xattrs;
+        xattrs.extend(self.xattrs);
+        Stat {
+            size: self.size.or(fallback.size),
+            mtime: self.mtime.or(fallback.mtime),
+            mode: self.mode.or(fallback.mode),
+            uid: self.uid.or(fallback.uid),
+            gid: self.gid.or(fallback.gid),
+            kind: self.kind.or(fallback.kind),
+            target: self.target.or(fallback.target),
+            xattrs,
+        }
+    }
+}
+
+/// Parses the output of a stat command, a JSON object such as
+/// `{"size": 1024, "mtime": 1600000000, "mode": "0755", "kind": "file"}`.
+///
+/// Every field is optional, and unknown fields are ignored.
+pub fn parse_stat(stdout: &[u8]) -> Result<Stat, String> {
+    let value: Value = serde_json::from_slice(stdout).map_err(|e| e.to_string())?;
+    if value.as_object().is_none() {
+        return Err("expected a JSON object".to_owned());
+    }
+    from_json(&value, "kind")
+}
+
+/// Reads the metadata fields of a JSON object, with `kind_field` naming the
+/// field which holds the file type.
+pub fn from_json(value: &Value, kind_field: &str) -> Result<Stat, String> {
+    let field = |name| value.get(name).filter(|value| !value.is_null());
+    let number = |name| match field(name) {
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or_else(|| format!("expected `{}` to be a non-negative integer", name)),
+        None => Ok(None),
+    };
+    let target = match field("target") {
+        Some(value) => Some(
+            value
+                .as_str()
+                .map(PathBuf::from)
+                .ok_or("expected `target` to be a string")?,
+        ),
+        None => None,
+    };
+    let mut xattrs = BTreeMap::new();
+    if let Some(value) = field("xattrs") {
+        let object = value
+            .as_object()
+            .ok_or("expected `xattrs` to be an object")?;
+        for (name, value) in object {
+            let value = value
+                .as_str()
+                .ok_or_else(|| format!("expected xattr `{}` to be a string", name))?;
+            xattrs.insert(name.clone(), value.as_bytes().to_vec());
+        }
+    }
+    Ok(Stat {
+        size: number("size")?,
+        mtime: field("mtime").map(parse_time).transpose()?,
+        mode: field("mode").map(parse_mode).transpose()?,
+        uid: number("uid")?.map(|uid| uid as u32),
+        gid: number("gid")?.map(|gid| gid as u32),
+        kind: field(kind_field)
+            .map(|value| parse_kind(value, kind_field))
+            .transpose()?,
+        target,
+        xattrs,
+    })
+}
+
+/// Parses seconds since the epoch, which may have a fractional part.
+fn parse_time(value: &Value) -> Result<SystemTime, String> {
+    value
+        .as_f64()
+        .filter(|secs| secs.is_finite() && *secs >= 0.0)
+        .map(|secs| UNIX_EPOCH + Duration::from_secs_f64(secs))
+        .ok_or_else(|| "expected `mtime` to be seconds since the epoch".to_owned())
+}
+
+/// Parses permission bits, given as a number or as an octal string such as
+/// `"0644"`. File type bits, as in `st_mode`, are dropped.
+fn parse_mode(value: &Value) -> Result<u16, String> {
+    let mode = match value.as_str() {
+        Some(octal) => u32::from_str_radix(octal, 8).ok(),
+        None => value.as_u64().map(|mode| mode as u32),
+    };
+    mode.map(|mode| (mode & 0o7777) as u16)
+        .ok_or_else(|| "expected `mode` to be a number or an octal string".to_owned())
+}
+
+fn parse_kind(value: &Value, field: &str) -> Result<FileType, String> {
+    match value.as_str() {
+        Some("file") => Ok(FileType::RegularFile),
+        Some("dir") => Ok(FileType::Directory),
+        Some("symlink") => Ok(FileType::Symlink),
+        _ => Err(format!(
+            "expected `{}` to be `file`, `dir` or `symlink`",
+            field
+        )),
+    }
+}